@@ -1,33 +1,210 @@
-use std::{env::args, process::ExitCode};
+use std::{env::args, fs::File, process::ExitCode};
 
+use g213_colours::capture::{CaptureLog, CaptureTransport};
 use g213_colours::commands::{
-    get_command, get_saved_command, save_command, Command, Run, Status, Successful,
+    error_status, parse_command, print_devices, restore_state, Checked, Command, Run, Status,
+    Successful,
 };
-use g213_colours::g213_keyboard::find_g213_keyboard;
+use g213_colours::config::{get_saved_config, migrate_legacy_files, save_command, set_config_file};
+use g213_colours::error::G213Error;
+use g213_colours::g213_keyboard::{list_keyboards, select_keyboards};
+use g213_colours::options::{parse_options, Options};
+use g213_colours::privileges::drop_privileges;
+use g213_colours::state::{LightingState, StateRecorder};
+use g213_colours::transport::{DryRunTransport, InterfaceGuard, NoKeyboard, Transport};
 
-fn main() -> ExitCode {
-    let device = find_g213_keyboard().expect("No G213 keyboard found, sorry!");
+fn run_or_restore<T: Transport>(
+    command: &Checked,
+    restore: Option<&LightingState>,
+    transport: &mut T,
+) -> Status {
+    match restore {
+        Some(state) => restore_state(transport, state),
+        None => command.run(transport),
+    }
+}
+
+// Runs the command, or puts back a saved state, recording the traffic if
+// --capture was given. Also returns what ended up on the keyboard.
+fn run_command<T: Transport>(
+    command: &Checked,
+    restore: Option<&LightingState>,
+    transport: &mut T,
+    capture: &mut Option<CaptureLog<File>>,
+) -> (Status, LightingState) {
+    let mut state = LightingState::default();
+
+    let status = {
+        let mut recorder = StateRecorder::new(transport, &mut state);
+
+        match capture {
+            Some(log) => run_or_restore(
+                command,
+                restore,
+                &mut CaptureTransport::new(&mut recorder, log),
+            ),
+            None => run_or_restore(command, restore, &mut recorder),
+        }
+    };
+
+    (status, state)
+}
 
+type Keyboard = InterfaceGuard<Box<dyn Transport>>;
+
+// The selected keyboards, opened and claimed while we still have the
+// privileges to. They stay claimed until the command is done.
+fn open_keyboards(options: &Options) -> Result<Vec<Keyboard>, G213Error> {
+    let mut keyboards = select_keyboards(options.device.as_ref())?;
+
+    if !options.all {
+        keyboards.truncate(1);
+    }
+
+    keyboards.into_iter().map(InterfaceGuard::claim).collect()
+}
+
+// Hands the keyboards back to the kernel, reporting any that won't go
+fn release_keyboards(keyboards: Vec<Keyboard>, status: Status) -> Status {
+    keyboards
+        .into_iter()
+        .fold(status, |status, keyboard| match keyboard.release() {
+            Ok(()) => status,
+            Err(err) => status.and(error_status(&err)),
+        })
+}
+
+fn main() -> ExitCode {
     let args = args().skip(1).collect::<Vec<_>>();
 
-    let mut command = get_command(&args);
+    let (options, args) = match parse_options(&args) {
+        Ok(parsed) => parsed,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return ExitCode::from(Status::Failure as u8);
+        }
+    };
+
+    let command = match parse_command(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(Status::Failure as u8);
+        }
+    };
+
+    let checked = match command.check() {
+        Ok(checked) => checked,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(Status::Failure as u8);
+        }
+    };
+
+    if options.dry_run && command.queries_device() {
+        eprintln!(
+            "'{}' needs a real keyboard, --dry-run only prints the packets a command would send",
+            command.name()
+        );
+        return ExitCode::from(Status::Failure as u8);
+    }
+
+    // A saved command always needs a keyboard, but we can't read it yet
+    let use_saved = matches!(command, Command::Unknown(_)) && !command.has_args();
+
+    let keyboards = if !options.dry_run && (use_saved || command.needs_device()) {
+        open_keyboards(&options)
+    } else {
+        Ok(vec![])
+    };
+
+    // Listing opens every keyboard, so that has to happen now too
+    let devices = matches!(command, Command::Devices).then(list_keyboards);
+
+    // Nothing below needs root
+    if let Err(err) = drop_privileges() {
+        eprintln!("{}", err);
+        return ExitCode::from(Status::Failure as u8);
+    }
+
+    set_config_file(options.config.clone());
+    migrate_legacy_files();
 
     // Use saved command if we have one and no command was specified
-    if let Command::Unknown(_) = &command {
-        if !command.has_args() {
-            if let Some(cmd) = get_saved_command() {
-                command = cmd;
+    let saved = if use_saved { get_saved_config() } else { None };
 
-                eprintln!("Using saved command: {}", command);
+    let (command, checked) = match &saved {
+        Some(config) => {
+            eprintln!("Using saved command: {}", config.command);
+
+            // Saved by an older, less fussy, version perhaps
+            match config.command.check() {
+                Ok(checked) => (&config.command, checked),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return ExitCode::from(Status::Failure as u8);
+                }
             }
         }
-    }
+        None => (&command, checked),
+    };
 
-    let cmd_status = command.run(&device);
+    // The exact colours from last time, unless we've been asked for new ones
+    let restore = saved
+        .as_ref()
+        .and_then(|config| config.state.as_ref())
+        .filter(|_| !options.reroll);
+
+    let mut capture = match options.capture.as_deref().map(CaptureLog::create) {
+        Some(Ok(log)) => Some(log),
+        Some(Err(err)) => {
+            eprintln!("{}", err);
+            return ExitCode::from(Status::Failure as u8);
+        }
+        None => None,
+    };
+
+    let mut state = None;
+
+    let cmd_status = if let Some(devices) = devices {
+        print_devices(devices)
+    } else if options.dry_run {
+        run_command(
+            &checked,
+            restore,
+            &mut DryRunTransport::default(),
+            &mut capture,
+        )
+        .0
+    } else if command.needs_device() {
+        let mut keyboards = match keyboards {
+            Ok(keyboards) => keyboards,
+            Err(err) => return ExitCode::from(error_status(&err) as u8),
+        };
+
+        let status = keyboards
+            .iter_mut()
+            .fold(Status::Success, |status, transport| {
+                let (kb_status, kb_state) = run_command(&checked, restore, transport, &mut capture);
+
+                // With --all, the first keyboard's colours are the ones saved
+                state.get_or_insert(kb_state);
+
+                status.and(kb_status)
+            });
+
+        release_keyboards(keyboards, status)
+    } else {
+        checked.run(&mut NoKeyboard)
+    };
 
     // Save the command for future use above, if it was successful
-    if Status::Success == cmd_status {
-        save_command(&command);
+    if Status::Success == cmd_status && !options.dry_run {
+        let state = state.filter(|state| !state.is_empty());
+
+        if let Err(err) = save_command(command, state) {
+            eprintln!("Unable to save command: {}", err);
+        }
     }
 
     if cmd_status.successful() {
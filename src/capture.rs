@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::device_profiles::DeviceProfile;
+use crate::error::G213Error;
+use crate::transport::{DeviceInfo, Transport};
+
+// A capture file has a line per packet, `seconds direction hex`, eg
+//
+//   # g213-cols capture, started 1700000000 (unix time)
+//   0.000412 > 11ff0c3a0001ff00ff0200000000000000000000
+//   0.001367 < 11ff0c3a0001ff00ff0200000000000000000000
+//
+// where `>` was sent to the keyboard and `<` came back from it
+
+const SENT: &str = ">";
+const RECEIVED: &str = "<";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureEntry {
+    pub seconds: f64,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+pub struct CaptureLog<W: Write> {
+    out: W,
+    start: Instant,
+}
+
+impl CaptureLog<File> {
+    pub fn create(path: &Path) -> Result<Self, G213Error> {
+        CaptureLog::new(File::create(path).map_err(|err| G213Error::file("create", path, err))?)
+    }
+}
+
+// The log isn't the keyboard, so its errors shouldn't look like the keyboard's
+fn log_error(err: std::io::Error) -> G213Error {
+    G213Error::Io(format!("Unable to write capture: {}", err))
+}
+
+impl<W: Write> CaptureLog<W> {
+    pub fn new(mut out: W) -> Result<Self, G213Error> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        writeln!(out, "# g213-cols capture, started {} (unix time)", started).map_err(log_error)?;
+
+        Ok(CaptureLog {
+            out,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn comment(&mut self, text: &str) -> Result<(), G213Error> {
+        writeln!(self.out, "# {}", text).map_err(log_error)
+    }
+
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> Result<(), G213Error> {
+        let marker = match direction {
+            Direction::Sent => SENT,
+            Direction::Received => RECEIVED,
+        };
+
+        writeln!(
+            self.out,
+            "{:.6} {} {}",
+            self.start.elapsed().as_secs_f64(),
+            marker,
+            hex::encode(bytes)
+        )
+        .map_err(log_error)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+pub fn parse_capture(text: &str) -> Result<Vec<CaptureEntry>, String> {
+    let mut entries = vec![];
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let bad_line = || format!("Bad capture line {}: {}", number + 1, line);
+
+        let parts: Vec<_> = line.split_ascii_whitespace().collect();
+
+        if parts.len() != 3 {
+            return Err(bad_line());
+        }
+
+        let seconds = parts[0].parse::<f64>().map_err(|_| bad_line())?;
+        let direction = match parts[1] {
+            SENT => Direction::Sent,
+            RECEIVED => Direction::Received,
+            _ => return Err(bad_line()),
+        };
+        let bytes = hex::decode(parts[2]).map_err(|_| bad_line())?;
+
+        entries.push(CaptureEntry {
+            seconds,
+            direction,
+            bytes,
+        });
+    }
+
+    Ok(entries)
+}
+
+// Records everything that passes through another transport
+pub struct CaptureTransport<'a, T: Transport + ?Sized, W: Write> {
+    inner: &'a mut T,
+    log: &'a mut CaptureLog<W>,
+}
+
+impl<'a, T: Transport + ?Sized, W: Write> CaptureTransport<'a, T, W> {
+    pub fn new(inner: &'a mut T, log: &'a mut CaptureLog<W>) -> Self {
+        CaptureTransport { inner, log }
+    }
+}
+
+impl<T: Transport + ?Sized, W: Write> Transport for CaptureTransport<'_, T, W> {
+    fn open(&mut self) -> Result<(), G213Error> {
+        self.inner.open()
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        self.inner.claim()
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        self.inner.release()
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        self.log.record(Direction::Sent, bytes)?;
+
+        self.inner.write_control(bytes)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        let len = self.inner.read_interrupt(bytes)?;
+
+        self.log.record(Direction::Received, &bytes[..len])?;
+
+        Ok(len)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        let info = self.inner.info()?;
+
+        self.log.comment(&format!(
+            "{} {} serial {} at {}",
+            info.manufacturer, info.product, info.serial, info.port_path
+        ))?;
+
+        Ok(info)
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        self.inner.profile()
+    }
+}
+
+#[cfg(test)]
+mod capture_tests {
+    use super::*;
+    use crate::g213_keyboard::set_keyboard_colour;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn capture_records_both_directions() {
+        let mut mock = MockTransport::new();
+        let mut log = CaptureLog::new(vec![]).unwrap();
+
+        set_keyboard_colour(&mut CaptureTransport::new(&mut mock, &mut log), 0xff00ff).unwrap();
+
+        let text = String::from_utf8(log.into_inner()).unwrap();
+        let entries = parse_capture(&text).unwrap();
+
+        let sent: Vec<_> = entries
+            .iter()
+            .filter(|e| e.direction == Direction::Sent)
+            .map(|e| e.bytes.clone())
+            .collect();
+        let received = entries
+            .iter()
+            .filter(|e| e.direction == Direction::Received)
+            .count();
+
+        assert_eq!(sent, mock.written);
+        assert_eq!(received, mock.written.len());
+        assert!(text.starts_with("# g213-cols capture"));
+    }
+
+    #[test]
+    fn parse_capture_lines() {
+        let text = "# comment\n\n0.000100 > 11ff0c3a\n0.000200 < 11ff0c3a\n";
+
+        assert_eq!(
+            parse_capture(text),
+            Ok(vec![
+                CaptureEntry {
+                    seconds: 0.0001,
+                    direction: Direction::Sent,
+                    bytes: vec![0x11, 0xff, 0x0c, 0x3a],
+                },
+                CaptureEntry {
+                    seconds: 0.0002,
+                    direction: Direction::Received,
+                    bytes: vec![0x11, 0xff, 0x0c, 0x3a],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_capture_rejects_bad_lines() {
+        assert!(parse_capture("0.1 > zz").is_err());
+        assert!(parse_capture("0.1 ? 11ff").is_err());
+        assert!(parse_capture("11ff").is_err());
+    }
+}
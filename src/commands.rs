@@ -1,26 +1,49 @@
-use dirs::home_dir;
-use libc::chown;
-use std::ffi::CString;
 use std::fmt::Display;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::path::Path;
 
-use rusb::{Device, GlobalContext};
 use serde::{Deserialize, Serialize};
-use users::{get_current_gid, get_current_uid};
+use users::get_current_uid;
 
+use crate::capture::{parse_capture, Direction};
+use crate::completions::completions;
+use crate::config::{config_paths, get_saved_command, get_saved_config, ConfigPaths, SavedConfig};
+use crate::error::G213Error;
 use crate::g213_keyboard::{
-    self, limit_speed, set_breathe, set_cycle, set_keyboard_colour, set_region_colour, show_info,
-    KeyboardRegions,
+    limit_speed, list_keyboards, select_keyboards, send_packets, send_raw_packets, set_breathe,
+    set_cycle, set_keyboard_colour, set_region_colour, set_region_colours, show_info, KeyboardList,
 };
-use crate::x11_colours::{get_x11_colour, get_x11_colours, x11_colour_names};
+use crate::help::{abbreviations_table, command_help, commands_table, options_table};
+use crate::profiles::{Profile, ProfileStore};
+use crate::registry::{command_suggestions, find_command, ArgError};
+use crate::state::LightingState;
+use crate::suggest::did_you_mean;
+use crate::transport::Transport;
+use crate::udev::{
+    check_access, install_rules, reload_udev, udev_rules, Access, DEFAULT_RULES_PATH,
+};
+use crate::watch::watch;
+use crate::x11_colours::{colour_suggestions, find_x11_colours, get_x11_colour, x11_colour_names};
 
 #[repr(u8)]
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Status {
     Success = 0,
     Failure,
     SuccessNoSave,
+    // No keyboard to run the command on - its own exit code, for scripts
+    NoDevice,
+}
+
+impl Status {
+    // Combined status when a command is run against several keyboards
+    pub fn and(self, other: Status) -> Status {
+        match (self, other) {
+            (Status::Failure, _) | (_, Status::Failure) => Status::Failure,
+            (Status::NoDevice, _) | (_, Status::NoDevice) => Status::NoDevice,
+            (Status::SuccessNoSave, _) | (_, Status::SuccessNoSave) => Status::SuccessNoSave,
+            _ => Status::Success,
+        }
+    }
 }
 
 pub trait Successful {
@@ -33,7 +56,7 @@ impl Successful for Status {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     Colour(Vec<String>),
     Region(Vec<String>),
@@ -42,7 +65,13 @@ pub enum Command {
     Cycle(Vec<String>),
     List(Vec<String>),
     Info,
+    Devices,
+    Watch,
+    Replay(Vec<String>),
+    SetupPermissions(Vec<String>),
+    Profile(Vec<String>),
     Saved,
+    Completions(Vec<String>),
     Help(Vec<String>),
     Unknown(Vec<String>),
 }
@@ -57,51 +86,171 @@ impl Display for Command {
             Command::Cycle(args) => write!(f, "cycle {}", args.join(" ")),
             Command::List(args) => write!(f, "list {}", args.join(" ")),
             Command::Info => write!(f, "info"),
+            Command::Devices => write!(f, "devices"),
+            Command::Watch => write!(f, "watch"),
+            Command::Replay(args) => write!(f, "replay {}", args.join(" ")),
+            Command::SetupPermissions(args) => {
+                write!(f, "setup-permissions {}", args.join(" "))
+            }
+            Command::Profile(args) => write!(f, "profile {}", args.join(" ")),
             Command::Saved => write!(f, "saved"),
+            Command::Completions(args) => write!(f, "completions {}", args.join(" ")),
             Command::Help(args) => write!(f, "help {}", args.join(" ")),
             Command::Unknown(args) => write!(f, "unknown {}", args.join(" ")),
         }
     }
 }
 
+impl Command {
+    // The registry name, see `registry::COMMANDS`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Colour(_) => "colour",
+            Command::Region(_) => "region",
+            Command::Regions(_) => "regions",
+            Command::Breathe(_) => "breathe",
+            Command::Cycle(_) => "cycle",
+            Command::List(_) => "list",
+            Command::Info => "info",
+            Command::Devices => "devices",
+            Command::Watch => "watch",
+            Command::Replay(_) => "replay",
+            Command::SetupPermissions(_) => "setup-permissions",
+            Command::Profile(_) => "profile",
+            Command::Saved => "saved",
+            Command::Completions(_) => "completions",
+            Command::Help(_) => "help",
+            Command::Unknown(_) => "unknown",
+        }
+    }
+
+    fn args(&self) -> &[String] {
+        match self {
+            Command::Colour(args)
+            | Command::Region(args)
+            | Command::Regions(args)
+            | Command::Breathe(args)
+            | Command::Cycle(args)
+            | Command::List(args)
+            | Command::Replay(args)
+            | Command::SetupPermissions(args)
+            | Command::Profile(args)
+            | Command::Completions(args)
+            | Command::Help(args)
+            | Command::Unknown(args) => args,
+            Command::Info | Command::Devices | Command::Watch | Command::Saved => &[],
+        }
+    }
+
+    // Checks the arguments against the command's registry entry. This is the
+    // only place they are checked, and only a checked command can be run.
+    pub fn check(&self) -> Result<Checked<'_>, ArgError> {
+        let numbers = match find_command(self.name()) {
+            Some(spec) => spec.validate(self.args())?,
+            None => vec![],
+        };
+
+        Ok(match (self, numbers.as_slice()) {
+            (Command::Region(args), &[region]) => Checked::Region(region as u8, &args[1..]),
+            (Command::Breathe(args), &[speed]) => Checked::Breathe(speed as u16, &args[1..]),
+            (Command::Cycle(_), &[speed]) => Checked::Cycle(speed as u16),
+            (Command::Region(_) | Command::Breathe(_) | Command::Cycle(_), _) => {
+                unreachable!("the registry gives these commands one number")
+            }
+            (Command::Colour(args), _) => Checked::Colour(args),
+            (Command::Regions(args), _) => Checked::Regions(args),
+            (Command::List(args), _) => Checked::List(args),
+            (Command::Info, _) => Checked::Info,
+            (Command::Devices, _) => Checked::Devices,
+            (Command::Watch, _) => Checked::Watch,
+            (Command::Replay(args), _) => Checked::Replay(args),
+            (Command::SetupPermissions(args), _) => Checked::SetupPermissions(args),
+            (Command::Profile(args), _) => Checked::Profile(args),
+            (Command::Saved, _) => Checked::Saved,
+            (Command::Completions(args), _) => Checked::Completions(args),
+            (Command::Help(args), _) => Checked::Help(args),
+            (Command::Unknown(args), _) => Checked::Unknown(args),
+        })
+    }
+}
+
+// A command that has passed `Command::check`, with its numbers parsed
+#[derive(Debug, PartialEq)]
+pub enum Checked<'a> {
+    Colour(&'a [String]),
+    Region(u8, &'a [String]),
+    Regions(&'a [String]),
+    Breathe(u16, &'a [String]),
+    Cycle(u16),
+    List(&'a [String]),
+    Info,
+    Devices,
+    Watch,
+    Replay(&'a [String]),
+    SetupPermissions(&'a [String]),
+    Profile(&'a [String]),
+    Saved,
+    Completions(&'a [String]),
+    Help(&'a [String]),
+    Unknown(&'a [String]),
+}
+
+impl Checked<'_> {
+    pub fn run<T: Transport>(&self, transport: &mut T) -> Status {
+        match *self {
+            Checked::Colour(args) => colour_command(transport, args),
+            Checked::Region(region, colour) => region_command(transport, region, colour),
+            Checked::Regions(args) => regions_command(transport, args),
+            Checked::Breathe(speed, colour) => breathe_command(transport, speed, colour),
+            Checked::Cycle(speed) => cycle_command(transport, speed),
+            Checked::List(args) => list_command(args),
+            Checked::Info => info_command(transport),
+            Checked::Devices => devices_command(),
+            Checked::Watch => watch_command(),
+            Checked::Replay(args) => replay_command(transport, args),
+            Checked::SetupPermissions(args) => setup_permissions_command(args),
+            Checked::Profile(args) => profile_command(transport, args),
+            Checked::Saved => saved_command(),
+            Checked::Completions(args) => completions_command(args),
+            Checked::Help(args) => help_command(args),
+            Checked::Unknown(args) => unknown_command(args),
+        }
+    }
+}
+
 pub fn get_command(args: &[String]) -> Command {
     let cmd = if args.is_empty() { "" } else { &args[0] };
 
-    match cmd.to_lowercase().as_str() {
-        "colour" | "c" => Command::Colour(args[1..].to_vec()),
-        "region" | "r" => Command::Region(args[1..].to_vec()),
-        "regions" | "rs" => Command::Regions(args[1..].to_vec()),
-        "breathe" | "b" => Command::Breathe(args[1..].to_vec()),
-        "cycle" | "cy" => Command::Cycle(args[1..].to_vec()),
-        "list" | "l" => Command::List(args[1..].to_vec()),
-        "info" | "i" => Command::Info,
-        "saved" | "s" => Command::Saved,
-        "help" | "h" | "?" => Command::Help(args[1..].to_vec()),
-        _ => Command::Unknown(args.to_vec()),
+    match find_command(cmd) {
+        Some(spec) => (spec.build)(spec.normalise(&args[1..])),
+        None => Command::Unknown(args.to_vec()),
+    }
+}
+
+// As `get_command`, but arguments given to a command that takes none are an
+// error rather than dropped - `Command::check` never gets to see them
+pub fn parse_command(args: &[String]) -> Result<Command, ArgError> {
+    match args.first().and_then(|cmd| find_command(cmd)) {
+        Some(spec) if spec.args.is_empty() && args.len() > 1 => Err(ArgError::TooMany {
+            command: spec.name,
+            usage: spec.usage(),
+        }),
+        _ => Ok(get_command(args)),
     }
 }
 
 pub trait Run {
-    fn run(&self, device: &Device<GlobalContext>) -> Status;
+    fn run<T: Transport>(&self, transport: &mut T) -> Status;
     fn has_args(&self) -> bool;
+    fn needs_device(&self) -> bool;
+    fn queries_device(&self) -> bool;
 }
 
 impl Run for Command {
-    fn run(&self, device: &Device<GlobalContext>) -> Status {
-        match self {
-            Command::Colour(args) => colour_command(device, args),
-            Command::Region(args) => region_command(device, args),
-            Command::Regions(args) => regions_command(device, args),
-            Command::Breathe(args) => breathe_command(device, args),
-            Command::Cycle(args) => cycle_command(device, args),
-            Command::List(args) => list_command(args),
-            Command::Info => info_command(device),
-            Command::Saved => saved_command(),
-            Command::Help(args) => help_command(args),
-            Command::Unknown(args) => {
-                eprintln!("Unknown command: {}", args.join(" "));
-                Status::SuccessNoSave
-            }
+    fn run<T: Transport>(&self, transport: &mut T) -> Status {
+        match self.check() {
+            Ok(checked) => checked.run(transport),
+            Err(err) => arg_status(err),
         }
     }
 
@@ -113,148 +262,146 @@ impl Run for Command {
             Command::Breathe(args) => !args.is_empty(),
             Command::Cycle(args) => !args.is_empty(),
             Command::List(args) => !args.is_empty(),
+            Command::Replay(args) => !args.is_empty(),
+            Command::SetupPermissions(args) => !args.is_empty(),
+            Command::Profile(args) => !args.is_empty(),
+            Command::Completions(args) => !args.is_empty(),
             Command::Help(args) => !args.is_empty(),
             Command::Unknown(args) => !args.is_empty(),
             _ => false,
         }
     }
-}
 
-// ----------------------------------------------------------------------------
+    fn needs_device(&self) -> bool {
+        if let Command::Profile(args) = self {
+            return args.first().is_some_and(|arg| arg == "load");
+        }
 
-const CONFIG_FILE: &str = ".g213-cols.json";
+        matches!(
+            self,
+            Command::Colour(_)
+                | Command::Region(_)
+                | Command::Regions(_)
+                | Command::Breathe(_)
+                | Command::Cycle(_)
+                | Command::Info
+                | Command::Replay(_)
+        )
+    }
 
-fn config_file_path() -> String {
-    match home_dir() {
-        Some(path) => format!("{}/{}", path.to_string_lossy(), CONFIG_FILE),
-        None => String::new(),
+    // Reports on real keyboards, so there is nothing it could show in a dry run
+    fn queries_device(&self) -> bool {
+        matches!(self, Command::Info | Command::Devices | Command::Watch)
     }
 }
 
-pub fn get_saved_command() -> Option<Command> {
-    let path = config_file_path();
-
-    let f = File::open(path);
+// ----------------------------------------------------------------------------
 
-    if let Ok(mut fh) = f {
-        let mut saved_cmd = String::new();
+// Nothing given, and nothing saved, is fine - anything else is a mistake
+fn unknown_command(args: &[String]) -> Status {
+    let Some(name) = args.first() else {
+        eprintln!("No saved command yet, try 'help'");
+        return Status::SuccessNoSave;
+    };
 
-        fh.read_to_string(&mut saved_cmd)
-            .expect("Unable to read saved command");
+    eprintln!(
+        "Unknown command '{}'{}",
+        name,
+        did_you_mean(&command_suggestions(name))
+    );
 
-        let command = serde_json::from_str(&saved_cmd).expect("Unable to use saved command");
+    Status::Failure
+}
 
-        return Some(command);
-    }
+// An unknown colour is reported, with any near misses
+fn unknown_colour(name: &str) -> Status {
+    eprintln!(
+        "Unknown colour '{}'{}",
+        name,
+        did_you_mean(&colour_suggestions(name))
+    );
 
-    None
+    Status::Failure
 }
 
-pub fn set_file_ownership_to_me(path: String) {
-    unsafe {
-        let c_path = CString::new(path).unwrap();
-        chown(c_path.as_ptr(), get_current_uid(), get_current_gid());
-    }
+fn get_colour(args: &[String]) -> Result<u32, Status> {
+    get_x11_colour(args).ok_or_else(|| unknown_colour(&args.join(" ")))
 }
 
-pub fn save_command(command: &Command) {
-    let ser_command = serde_json::to_string(&command).unwrap();
-    let path = config_file_path();
-
-    let mut f = File::create(&path).expect("Unable to open config file for saving");
-
-    Write::write_all(&mut f, ser_command.as_bytes()).expect("Unable to save command");
-
-    set_file_ownership_to_me(path);
+fn get_colours(args: &[String], num: u8) -> Result<Vec<u32>, Status> {
+    find_x11_colours(args, num).map_err(|name| unknown_colour(&name))
 }
 
-// ----------------------------------------------------------------------------
+// The status for a keyboard error, having reported it
+pub fn error_status(err: &G213Error) -> Status {
+    eprintln!("{}", err);
 
-const RED: u32 = 0xff1010;
-
-fn get_colour_or_red(args: &[String]) -> (u32, Status) {
-    match get_x11_colour(args) {
-        Some(col) => (col, Status::Success),
-        None => (RED, Status::Failure),
+    if err.is_no_device() {
+        Status::NoDevice
+    } else {
+        Status::Failure
     }
 }
 
-fn get_colours_or_red(args: &[String], num: u8) -> (Vec<u32>, Status) {
-    match get_x11_colours(args, num) {
-        Some(cols) => (cols, Status::Success),
-        None => (vec![RED; num as usize], Status::Failure),
+// Report any keyboard failure, otherwise keep the status we already have
+fn keyboard_status(result: Result<(), G213Error>, status: Status) -> Status {
+    match result {
+        Ok(()) => status,
+        Err(err) => error_status(&err),
     }
 }
 
-fn colour_command(device: &Device<GlobalContext>, args: &[String]) -> Status {
-    let (colour, status) = get_colour_or_red(args);
-
-    set_keyboard_colour(device, colour);
+fn colour_command<T: Transport>(transport: &mut T, args: &[String]) -> Status {
+    let colour = match get_colour(args) {
+        Ok(colour) => colour,
+        Err(status) => return status,
+    };
 
-    status
+    keyboard_status(set_keyboard_colour(transport, colour), Status::Success)
 }
 
-fn region_command(device: &Device<GlobalContext>, args: &[String]) -> Status {
-    let mut status = Status::Failure;
+// Report a bad argument
+fn arg_status(err: ArgError) -> Status {
+    eprintln!("{}", err);
 
-    if !args.is_empty() {
-        let region: KeyboardRegions = args[0].parse::<u8>().unwrap().into();
-
-        let (colour, col_status) = get_colour_or_red(&args[1..]);
-
-        set_region_colour(device, region as u8, colour);
-
-        status = col_status;
-    } else {
-        eprintln!("At least one - 'region' ['colour'] - argument needed for 'region' command");
-    }
-
-    status
+    Status::Failure
 }
 
-fn regions_command(device: &Device<GlobalContext>, args: &[String]) -> Status {
-    let (colours, status) = get_colours_or_red(args, g213_keyboard::NUM_REGIONS);
-
-    colours
-        .iter()
-        .enumerate()
-        .for_each(|(region, colour)| set_region_colour(device, (region + 1) as u8, *colour));
+fn region_command<T: Transport>(transport: &mut T, region: u8, colour: &[String]) -> Status {
+    let colour = match get_colour(colour) {
+        Ok(colour) => colour,
+        Err(status) => return status,
+    };
 
-    status
+    keyboard_status(
+        set_region_colour(transport, region, colour),
+        Status::Success,
+    )
 }
 
-fn breathe_command(device: &Device<GlobalContext>, args: &[String]) -> Status {
-    let mut status = Status::Failure;
-
-    if !args.is_empty() {
-        let speed = limit_speed(args[0].parse::<u16>().unwrap());
-
-        let (colour, col_status) = get_colour_or_red(&args[1..]);
-
-        set_breathe(device, speed, colour);
-
-        status = col_status;
-    } else {
-        eprintln!("At least one - 'speed' ['colour'] - argument needed for 'breathe' command");
-    }
+fn regions_command<T: Transport>(transport: &mut T, args: &[String]) -> Status {
+    let colours = match get_colours(args, transport.profile().num_regions) {
+        Ok(colours) => colours,
+        Err(status) => return status,
+    };
 
-    status
+    keyboard_status(set_region_colours(transport, &colours), Status::Success)
 }
 
-fn cycle_command(device: &Device<GlobalContext>, args: &[String]) -> Status {
-    let mut status = Status::Failure;
-
-    if args.len() == 1 {
-        let speed = limit_speed(args[0].parse::<u16>().unwrap());
+fn breathe_command<T: Transport>(transport: &mut T, speed: u16, colour: &[String]) -> Status {
+    let speed = limit_speed(speed);
+    let colour = match get_colour(colour) {
+        Ok(colour) => colour,
+        Err(status) => return status,
+    };
 
-        set_cycle(device, speed);
+    keyboard_status(set_breathe(transport, speed, colour), Status::Success)
+}
 
-        status = Status::Success;
-    } else {
-        eprintln!("One 'speed' argument needed for 'cycle' command");
-    }
+fn cycle_command<T: Transport>(transport: &mut T, speed: u16) -> Status {
+    let speed = limit_speed(speed);
 
-    status
+    keyboard_status(set_cycle(transport, speed), Status::Success)
 }
 
 fn list_command(args: &[String]) -> Status {
@@ -292,72 +439,319 @@ fn saved_command() -> Status {
     Status::SuccessNoSave
 }
 
-fn info_command(device: &Device<GlobalContext>) -> Status {
-    println!("Device bus:   {}", device.bus_number());
-    println!("Device #:     {}", device.address());
-    println!("Device speed: {:?}", device.speed());
+fn info_command<T: Transport>(transport: &mut T) -> Status {
+    match show_info(transport) {
+        Ok(info) => {
+            println!("{}", info);
+            Status::SuccessNoSave
+        }
+        Err(err) => error_status(&err),
+    }
+}
 
-    // Bit hacky, directly outputs info
-    show_info(device);
+fn devices_command() -> Status {
+    print_devices(list_keyboards())
+}
 
-    Status::SuccessNoSave
+// The keyboards are listed up front, while we can still open them, see main
+pub fn print_devices(keyboards: Result<KeyboardList, G213Error>) -> Status {
+    let keyboards = match keyboards {
+        Ok(keyboards) => keyboards,
+        Err(err) => return error_status(&err),
+    };
+
+    if keyboards.is_empty() {
+        return error_status(&G213Error::NotFound);
+    }
+
+    let details: Vec<_> = keyboards
+        .iter()
+        .map(|(location, info)| match info {
+            Ok(info) => info.to_string(),
+            Err(err) => format!("{}: {}", location, err),
+        })
+        .collect();
+
+    println!("{}", details.join("\n\n"));
+
+    if keyboards.iter().all(|(_, info)| info.is_err()) {
+        Status::Failure
+    } else {
+        Status::SuccessNoSave
+    }
+}
+
+// Puts back exactly what a saved command left on the keyboard
+pub fn restore_state<T: Transport>(transport: &mut T, state: &LightingState) -> Status {
+    keyboard_status(send_packets(transport, &state.packets), Status::Success)
+}
+
+// Restore the saved lighting on every connected keyboard
+fn restore_saved_command() {
+    let Some(config) = get_saved_config() else {
+        eprintln!("Keyboard connected, but there is no saved command");
+        return;
+    };
+
+    match select_keyboards(None) {
+        Ok(keyboards) => {
+            for mut transport in keyboards {
+                eprintln!(
+                    "Keyboard connected, using saved command: {}",
+                    config.command
+                );
+
+                match &config.state {
+                    Some(state) => restore_state(&mut transport, state),
+                    None => config.command.run(&mut transport),
+                };
+            }
+        }
+        Err(err) => eprintln!("{}", err),
+    }
 }
 
-fn help_command(_args: &[String]) -> Status {
+fn watch_command() -> Status {
+    eprintln!("Watching for G213 keyboards, press Ctrl-C to stop");
+
+    match watch(restore_saved_command) {
+        Ok(()) => Status::SuccessNoSave,
+        Err(err) => error_status(&err),
+    }
+}
+
+fn replay_command<T: Transport>(transport: &mut T, args: &[String]) -> Status {
+    if args.len() != 1 {
+        eprintln!("One 'capture file' argument needed for 'replay' command");
+        return Status::Failure;
+    }
+
+    let entries = match std::fs::read_to_string(&args[0]) {
+        Ok(text) => parse_capture(&text),
+        Err(err) => Err(format!("Unable to read {}: {}", args[0], err)),
+    };
+
+    match entries {
+        Ok(entries) => {
+            let packets: Vec<_> = entries
+                .into_iter()
+                .filter(|entry| entry.direction == Direction::Sent)
+                .map(|entry| entry.bytes)
+                .collect();
+
+            keyboard_status(send_raw_packets(transport, &packets), Status::SuccessNoSave)
+        }
+        Err(msg) => {
+            eprintln!("{}", msg);
+            Status::Failure
+        }
+    }
+}
+
+fn report_access() -> Status {
+    let nodes = check_access();
+
+    if nodes.is_empty() {
+        println!("No supported keyboards connected, unable to check access");
+        return Status::SuccessNoSave;
+    }
+
+    let mut status = Status::SuccessNoSave;
+
+    for (path, ok) in nodes {
+        if ok {
+            println!("{} - ok", path.display());
+        } else {
+            println!("{} - no access", path.display());
+            status = Status::Failure;
+        }
+    }
+
+    if get_current_uid() == 0 {
+        println!("NOTE: checked as root, run `g213-cols devices` as yourself to be sure");
+    }
+
+    status
+}
+
+// setup-permissions [seat | group] [file | -]
+fn setup_permissions_command(args: &[String]) -> Status {
+    if args.len() > 2 {
+        eprintln!("At most two - ['seat' or 'group'] ['file'] - arguments for 'setup-permissions' command");
+        return Status::Failure;
+    }
+
+    // A lone '-' prints the seat rules
+    let (access, path) = match args {
+        [] => (Ok(Access::Seat), DEFAULT_RULES_PATH),
+        [path] if path == "-" => (Ok(Access::Seat), "-"),
+        [access] => (Access::parse(access), DEFAULT_RULES_PATH),
+        [access, path, ..] => (Access::parse(access), path.as_str()),
+    };
+
+    let access = match access {
+        Ok(access) => access,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return Status::Failure;
+        }
+    };
+
+    let rules = udev_rules(&access);
+
+    if path == "-" {
+        print!("{}", rules);
+        return Status::SuccessNoSave;
+    }
+
+    if let Err(err) = install_rules(Path::new(path), &rules) {
+        eprintln!("{}", err);
+        return Status::Failure;
+    }
+
+    println!("Wrote udev rules to {}", path);
+
+    if let Err(err) = reload_udev() {
+        eprintln!("{}", err);
+        eprintln!(
+            "Run `udevadm control --reload-rules && udevadm trigger`, or replug the keyboard"
+        );
+    }
+
+    report_access()
+}
+
+fn load_profile<T: Transport>(transport: &mut T, name: &str, profile: &Profile) -> Status {
+    match (&profile.state, &profile.command) {
+        (Some(state), _) => restore_state(transport, state),
+        (None, Command::Profile(_)) => {
+            eprintln!("Profile '{}' would load another profile", name);
+            Status::Failure
+        }
+        (None, command) => command.run(transport),
+    }
+}
+
+// profile save|load|rm name, profile list - `saved` is only asked for when saving.
+// Profiles in the system config directory can be listed and loaded, not changed.
+fn run_profile_command<T: Transport>(
+    transport: &mut T,
+    paths: &ConfigPaths,
+    args: &[String],
+    saved: impl FnOnce() -> Option<SavedConfig>,
+) -> Result<Status, G213Error> {
+    let path = paths.profiles_file();
+    let mut store = match &path {
+        Some(path) => ProfileStore::load(path)?,
+        None => ProfileStore::default(),
+    };
+    let system = ProfileStore::load(&paths.system_profiles_file())?;
+
+    let user_path =
+        || path.ok_or_else(|| G213Error::Io("Unable to find a config directory".to_string()));
+
+    let action = args.first().map_or("", |arg| arg.as_str());
+    let name = match args.len() {
+        2 => args[1].as_str(),
+        _ => "",
+    };
+
+    match (action, name) {
+        ("list", "") if args.len() == 1 => {
+            for name in store.names() {
+                println!("{}", name);
+            }
+
+            for name in system.names().filter(|name| store.get(name).is_none()) {
+                println!("{} (system)", name);
+            }
+        }
+        ("save", name) if !name.is_empty() => {
+            let Some(config) = saved() else {
+                eprintln!("No saved command to keep as profile '{}'", name);
+                return Ok(Status::Failure);
+            };
+
+            store.insert(
+                name,
+                Profile {
+                    command: config.command,
+                    state: config.state,
+                },
+            );
+            store.save(&user_path()?)?;
+
+            println!("Saved profile '{}'", name);
+        }
+        ("load", name) if !name.is_empty() => match store.get(name).or(system.get(name)) {
+            Some(profile) => return Ok(load_profile(transport, name, profile)),
+            None => {
+                eprintln!("No profile called '{}'", name);
+                return Ok(Status::Failure);
+            }
+        },
+        ("rm", name) if !name.is_empty() => {
+            if store.remove(name).is_none() {
+                eprintln!("No profile called '{}' that can be removed", name);
+                return Ok(Status::Failure);
+            }
+
+            store.save(&user_path()?)?;
+
+            println!("Removed profile '{}'", name);
+        }
+        _ => {
+            eprintln!(
+                "'profile' command needs one of - 'save name', 'load name', 'list' or 'rm name'"
+            );
+            return Ok(Status::Failure);
+        }
+    }
+
+    Ok(Status::SuccessNoSave)
+}
+
+fn profile_command<T: Transport>(transport: &mut T, args: &[String]) -> Status {
+    match run_profile_command(transport, &config_paths(), args, get_saved_config) {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("{}", err);
+            Status::Failure
+        }
+    }
+}
+
+fn completions_command(args: &[String]) -> Status {
+    match args.first().and_then(|shell| completions(shell)) {
+        Some(script) => {
+            print!("{}", script);
+            Status::SuccessNoSave
+        }
+        None => {
+            eprintln!("'completions' command needs one of - 'bash', 'zsh' or 'fish'");
+            Status::Failure
+        }
+    }
+}
+
+fn help_command(args: &[String]) -> Status {
     const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-    println!(
-        "\ng213-cols - version {} ― Found a G213 keyboard ✅",
-        VERSION
-    );
+    if let Some(name) = args.first() {
+        return match find_command(name) {
+            Some(spec) => {
+                println!("{}", command_help(spec));
+                Status::SuccessNoSave
+            }
+            None => unknown_command(args),
+        };
+    }
 
-    println!(
-        r#"
-+ ----------------------------------------------------- + ------------------------------------------------------------- +
-| Command                                               | Details                                                       |
-| ----------------------------------------------------- + ------------------------------------------------------------- |
-| Set whole keyboard colour                             |                                                               |
-| `g213-cols colour`                                    | sets the default 'white'                                      |
-| `g213-cols colour ffff00`                             | sets the specified hex colour, eg yellow                      |
-|                                                       | NOTE: 3 digit hex colour conversion - 1af -> 11aaff           |
-| `g213-cols colour lawn green`                         | sets the named X11 colour                                     |
-| `g213-cols colour "alice blue"`                       | sets the named X11 colour - as a single argument              |
-| `g213-cols colour dark_slate_blue`                    | sets the named X11 colour - underscores become spaces         |
-| `g213-cols colour random`                             | sets a random colour                                          |
-| Set the colour of a specific keyboard region - 1 to 5 |                                                               |
-| `g213-cols region 2 [colour]`                         | sets the region to the [colour] as defined above              |
-| Set the colours of all 5 keyboard regions             |                                                               |
-| `g213-cols regions [colour colour ...]`               | sets the regions to the [colours] as defined above            |
-|                                                       | last supplied colour is repeated to set all 5 regions         |
-| Set 'breathe' mode                                    |                                                               |
-| `g213-cols breathe 1000 [colour]`                     | sets the breathe time (in ms) for the [colour]                |
-| Set 'cycle' mode                                      |                                                               |
-| `g213-cols cycle 1000`                                | sets the cycle time (in ms) all colours                       |
-| Use last successful saved command                     |                                                               |
-| `g213-cols`                                           | Runs the last successful saved command from ~/.g213-cols.json |
-| Show saved command                                    |                                                               |
-| `g213-cols saved`                                     | Display the last successful saved command                     |
-| Show keyboard info                                    |                                                               |
-| `g213-cols info`                                      | Show USB info for keyboard                                    |
-| List X11 colours                                      |                                                               |
-| `g213-cols list [name]`                               | Show X11 colours that contain optional [name] or all colours  |
-+ ----------------------------------------------------- + ------------------------------------------------------------- +
-
-+ ------- + ------------ +
-| Command | Abbreviation |
-| ------- + ------------ |
-| colour  | c            |
-| region  | r            |
-| regions | rs           |
-| breathe | b            |
-| cycle   | cy           |
-| saved   | s            |
-| info    | i            |
-| list    | l            |
-| help    | h or ?       |
-+ ------- + ------------ +
-"#
-    );
+    println!("\ng213-cols - version {}\n", VERSION);
+    println!("{}", commands_table(true));
+    println!("{}", abbreviations_table(true));
+    println!("{}", options_table(true));
+    println!("Use 'help command' for the details of one command");
 
     Status::SuccessNoSave
 }
@@ -366,6 +760,8 @@ fn help_command(_args: &[String]) -> Status {
 mod commands_tests {
 
     use super::*;
+    use crate::packet::Packet;
+    use crate::transport::MockTransport;
 
     fn to_string_vec(words: Vec<&str>) -> Vec<String> {
         words.iter().map(|s| s.to_string()).collect()
@@ -377,10 +773,7 @@ mod commands_tests {
 
         let cmd = get_command(&args);
 
-        assert!(match cmd {
-            Command::Colour(_) => true,
-            _ => false,
-        });
+        assert!(matches!(cmd, Command::Colour(_)));
 
         assert!(!cmd.has_args());
     }
@@ -391,25 +784,412 @@ mod commands_tests {
 
         let cmd = get_command(&args);
 
-        assert!(match cmd {
-            Command::Colour(_) => true,
-            _ => false,
-        });
+        assert!(matches!(cmd, Command::Colour(_)));
 
         assert!(cmd.has_args());
     }
 
+    #[test]
+    fn combined_status() {
+        assert_eq!(Status::Success.and(Status::Success), Status::Success);
+        assert_eq!(Status::Success.and(Status::Failure), Status::Failure);
+        assert_eq!(
+            Status::SuccessNoSave.and(Status::Success),
+            Status::SuccessNoSave
+        );
+        assert_eq!(Status::Failure.and(Status::SuccessNoSave), Status::Failure);
+    }
+
+    #[test]
+    fn no_device_status() {
+        assert_eq!(Status::Success.and(Status::NoDevice), Status::NoDevice);
+        assert_eq!(Status::NoDevice.and(Status::Failure), Status::Failure);
+        assert!(!Status::NoDevice.successful());
+        assert_eq!(error_status(&G213Error::NotFound), Status::NoDevice);
+        assert_eq!(error_status(&G213Error::Timeout), Status::Failure);
+    }
+
     #[test]
     fn successful_status() {
         let res = Status::Success.successful() && Status::SuccessNoSave.successful();
 
-        assert_eq!(res, true);
+        assert!(res);
     }
 
     #[test]
     fn unsuccessful_status() {
         let res = Status::Failure.successful();
 
-        assert_eq!(res, false);
+        assert!(!res);
+    }
+
+    #[test]
+    fn colour_command_sends_packet() {
+        let mut mock = MockTransport::new();
+        let cmd = get_command(&to_string_vec(vec!["colour", "ff00ff"]));
+
+        assert!(cmd.run(&mut mock) == Status::Success);
+        assert_eq!(
+            mock.effect_packets(),
+            vec![hex::decode("11ff0c3a0001ff00ff0200000000000000000000").unwrap()]
+        );
+        assert_eq!(mock.claims, 1);
+        assert_eq!(mock.releases, 1);
+    }
+
+    #[test]
+    fn regions_command_sends_a_packet_per_region() {
+        let mut mock = MockTransport::new();
+        let cmd = get_command(&to_string_vec(vec!["regions", "red", "blue"]));
+
+        assert!(cmd.run(&mut mock) == Status::Success);
+        assert_eq!(
+            mock.effect_packets().len(),
+            crate::g213_keyboard::NUM_REGIONS as usize
+        );
+        assert_eq!(mock.claims, 1);
+        assert_eq!(
+            mock.effect_packets()[4],
+            hex::decode("11ff0c3a05010000ff0200000000000000000000").unwrap()
+        );
+    }
+
+    #[test]
+    fn breathe_command_sends_packet() {
+        let mut mock = MockTransport::new();
+        let cmd = get_command(&to_string_vec(vec!["breathe", "1000", "00ff00"]));
+
+        assert!(cmd.run(&mut mock) == Status::Success);
+        assert_eq!(
+            mock.effect_packets(),
+            vec![hex::decode("11ff0c3a000200ff0003e8006400000000000000").unwrap()]
+        );
+    }
+
+    #[test]
+    fn cycle_command_sends_packet() {
+        let mut mock = MockTransport::new();
+        let cmd = get_command(&to_string_vec(vec!["cycle", "10"]));
+
+        assert!(cmd.run(&mut mock) == Status::Success);
+        assert_eq!(
+            mock.effect_packets(),
+            vec![hex::decode("11ff0c3a0003ffffff0000002064000000000000").unwrap()]
+        );
+    }
+
+    #[test]
+    fn keyboard_failure_is_a_failed_status() {
+        let mut mock = MockTransport::new();
+        mock.fail_writes = Some(G213Error::Timeout);
+
+        let cmd = get_command(&to_string_vec(vec!["colour", "red"]));
+
+        assert!(cmd.run(&mut mock) == Status::Failure);
+        assert!(!mock.claimed);
+    }
+
+    #[test]
+    fn replay_command_sends_captured_packets() {
+        let path = std::env::temp_dir().join(format!("g213-replay-{}.cap", std::process::id()));
+        std::fs::write(
+            &path,
+            "# capture\n\
+             0.000100 > 11ff0c3a0001ff00ff0200000000000000000000\n\
+             0.000200 < 11ff0c3a0001ff00ff0200000000000000000000\n",
+        )
+        .unwrap();
+
+        let mut mock = MockTransport::new();
+        let cmd = get_command(&to_string_vec(vec!["replay", path.to_str().unwrap()]));
+
+        assert!(cmd.run(&mut mock) == Status::SuccessNoSave);
+        assert_eq!(
+            mock.written,
+            vec![hex::decode("11ff0c3a0001ff00ff0200000000000000000000").unwrap()]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn setup_permissions_can_print_rules() {
+        let cmd = get_command(&to_string_vec(vec!["sp", "plugdev", "-"]));
+
+        assert!(matches!(cmd, Command::SetupPermissions(_)));
+        assert!(!cmd.needs_device());
+        assert!(cmd.run(&mut MockTransport::new()) == Status::SuccessNoSave);
+    }
+
+    #[test]
+    fn setup_permissions_dash_prints_seat_rules() {
+        let cmd = get_command(&to_string_vec(vec!["setup-permissions", "-"]));
+
+        assert!(cmd.run(&mut MockTransport::new()) == Status::SuccessNoSave);
+    }
+
+    #[test]
+    fn setup_permissions_rejects_bad_groups() {
+        for args in [vec!["sp", "-x"], vec!["sp", "bad group", "-"]] {
+            let cmd = get_command(&to_string_vec(args));
+
+            assert!(cmd.run(&mut MockTransport::new()) == Status::Failure);
+        }
+    }
+
+    #[test]
+    fn restore_state_sends_saved_packets() {
+        let mut mock = MockTransport::new();
+        let state = LightingState {
+            packets: vec![Packet::Colour {
+                region: 0,
+                colour: 0xff00ff,
+            }],
+        };
+
+        assert!(restore_state(&mut mock, &state) == Status::Success);
+        assert_eq!(
+            mock.effect_packets(),
+            vec![hex::decode("11ff0c3a0001ff00ff0200000000000000000000").unwrap()]
+        );
+    }
+
+    fn temp_config(name: &str) -> ConfigPaths {
+        let dir =
+            std::env::temp_dir().join(format!("g213-profile-cmd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        ConfigPaths {
+            explicit: None,
+            user_dir: Some(dir.join("user")),
+            system_dir: dir.join("system"),
+        }
+    }
+
+    fn remove_config(paths: ConfigPaths) {
+        std::fs::remove_dir_all(paths.system_dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn profile_save_then_load() {
+        let paths = temp_config("save-load");
+        let mut mock = MockTransport::new();
+
+        let saved = || {
+            Some(SavedConfig {
+                version: crate::config::CONFIG_VERSION,
+                command: get_command(&to_string_vec(vec!["colour", "random"])),
+                state: Some(LightingState {
+                    packets: vec![Packet::Colour {
+                        region: 0,
+                        colour: 0xff00ff,
+                    }],
+                }),
+            })
+        };
+
+        let status = run_profile_command(
+            &mut mock,
+            &paths,
+            &to_string_vec(vec!["save", "focus"]),
+            saved,
+        );
+
+        assert_eq!(status, Ok(Status::SuccessNoSave));
+        assert!(mock.written.is_empty());
+
+        let status = run_profile_command(
+            &mut mock,
+            &paths,
+            &to_string_vec(vec!["load", "focus"]),
+            || None,
+        );
+
+        assert_eq!(status, Ok(Status::Success));
+        assert_eq!(
+            mock.effect_packets(),
+            vec![hex::decode("11ff0c3a0001ff00ff0200000000000000000000").unwrap()]
+        );
+
+        remove_config(paths);
+    }
+
+    #[test]
+    fn system_profile_without_state_runs_command() {
+        let paths = temp_config("no-state");
+        let mut store = ProfileStore::default();
+
+        store.insert(
+            "gaming",
+            Profile {
+                command: get_command(&to_string_vec(vec!["cycle", "10"])),
+                state: None,
+            },
+        );
+        store.save(&paths.system_profiles_file()).unwrap();
+
+        let mut mock = MockTransport::new();
+        let status = run_profile_command(
+            &mut mock,
+            &paths,
+            &to_string_vec(vec!["load", "gaming"]),
+            || None,
+        );
+
+        assert_eq!(status, Ok(Status::Success));
+        assert_eq!(
+            mock.effect_packets(),
+            vec![hex::decode("11ff0c3a0003ffffff0000002064000000000000").unwrap()]
+        );
+
+        // System profiles can't be removed
+        let status = run_profile_command(
+            &mut mock,
+            &paths,
+            &to_string_vec(vec!["rm", "gaming"]),
+            || None,
+        );
+
+        assert_eq!(status, Ok(Status::Failure));
+
+        remove_config(paths);
+    }
+
+    #[test]
+    fn profile_rm_and_bad_usage() {
+        let paths = temp_config("rm");
+        let mut mock = MockTransport::new();
+        let args = to_string_vec(vec!["rm", "missing"]);
+
+        assert_eq!(
+            run_profile_command(&mut mock, &paths, &args, || None),
+            Ok(Status::Failure)
+        );
+        assert_eq!(
+            run_profile_command(&mut mock, &paths, &to_string_vec(vec!["load"]), || None),
+            Ok(Status::Failure)
+        );
+        assert!(!get_command(&to_string_vec(vec!["profile", "list"])).needs_device());
+        assert!(get_command(&to_string_vec(vec!["p", "load", "x"])).needs_device());
+    }
+
+    #[test]
+    fn bad_arguments_fail_without_panicking() {
+        for args in [
+            vec!["region", "x", "red"],
+            vec!["region", "9", "red"],
+            vec!["region"],
+            vec!["breathe", "fast"],
+            vec!["cycle", "1000", "2000"],
+        ] {
+            let mut mock = MockTransport::new();
+            let cmd = get_command(&to_string_vec(args));
+
+            assert!(cmd.check().is_err());
+            assert!(cmd.run(&mut mock) == Status::Failure);
+            assert!(mock.written.is_empty());
+        }
+    }
+
+    #[test]
+    fn unknown_colour_sends_nothing() {
+        for args in [
+            vec!["colour", "lawngren"],
+            vec!["region", "2", "bluee"],
+            vec!["regions", "red", "bluee"],
+            vec!["breathe", "1000", "nonsense"],
+        ] {
+            let mut mock = MockTransport::new();
+
+            assert!(get_command(&to_string_vec(args)).run(&mut mock) == Status::Failure);
+            assert!(mock.written.is_empty());
+        }
+    }
+
+    #[test]
+    fn unknown_command_fails() {
+        let mut mock = MockTransport::new();
+
+        assert!(get_command(&to_string_vec(vec!["colr"])).run(&mut mock) == Status::Failure);
+        assert!(get_command(&[]).run(&mut mock) == Status::SuccessNoSave);
+    }
+
+    #[test]
+    fn devices_lists_the_ones_it_can_open() {
+        use crate::transport::DeviceInfo;
+
+        let readable = || ("USB bus 1 address 5".to_string(), Ok(DeviceInfo::default()));
+        let unreadable = || {
+            (
+                "USB bus 1 address 6".to_string(),
+                Err(G213Error::PermissionDenied),
+            )
+        };
+
+        assert_eq!(
+            print_devices(Ok(vec![unreadable(), readable()])),
+            Status::SuccessNoSave
+        );
+        assert_eq!(print_devices(Ok(vec![unreadable()])), Status::Failure);
+        assert_eq!(print_devices(Ok(vec![])), Status::NoDevice);
+    }
+
+    #[test]
+    fn help_for_one_command() {
+        let mut mock = MockTransport::new();
+
+        assert!(
+            get_command(&to_string_vec(vec!["help", "cy"])).run(&mut mock) == Status::SuccessNoSave
+        );
+        assert!(
+            get_command(&to_string_vec(vec!["help", "bogus"])).run(&mut mock) == Status::Failure
+        );
+    }
+
+    #[test]
+    fn check_parses_numbers() {
+        let red = to_string_vec(vec!["red"]);
+
+        assert!(parse_command(&to_string_vec(vec!["info", "extra"])).is_err());
+        assert_eq!(
+            get_command(&to_string_vec(vec!["r", "2", "red"])).check(),
+            Ok(Checked::Region(2, &red))
+        );
+        assert_eq!(
+            get_command(&to_string_vec(vec!["cycle", "5000"])).check(),
+            Ok(Checked::Cycle(5000))
+        );
+        assert!(matches!(
+            get_command(&to_string_vec(vec!["nonsense"])).check(),
+            Ok(Checked::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn upper_case_subcommands_are_understood() {
+        let cmd = get_command(&to_string_vec(vec!["profile", "LOAD", "x"]));
+
+        assert!(cmd.check().is_ok());
+        assert!(cmd.needs_device());
+        assert_eq!(cmd.to_string(), "profile load x");
+    }
+
+    #[test]
+    fn only_reports_query_the_device() {
+        for cmd in ["info", "devices", "watch"] {
+            assert!(get_command(&to_string_vec(vec![cmd])).queries_device());
+        }
+
+        assert!(!get_command(&to_string_vec(vec!["colour", "red"])).queries_device());
+        assert!(!get_command(&to_string_vec(vec!["list"])).queries_device());
+    }
+
+    #[test]
+    fn list_command_does_not_touch_device() {
+        let mut mock = MockTransport::new();
+        let cmd = get_command(&to_string_vec(vec!["list", "lawn"]));
+
+        assert!(cmd.run(&mut mock) == Status::SuccessNoSave);
+        assert!(!mock.opened);
     }
 }
@@ -0,0 +1,550 @@
+use crate::options::OPTION_HELP;
+use crate::registry::{ArgKind, CommandSpec, COMMANDS};
+use crate::x11_colours::x11_colour_names;
+
+// What can be offered for an argument
+enum Values {
+    Commands,
+    Words(Vec<String>),
+    Colours,
+    Profiles,
+    Files,
+}
+
+fn values(kind: &ArgKind) -> Option<Values> {
+    match kind {
+        // Regions, but not speeds
+        ArgKind::Number { min, max } if max - min < 16 => Some(Values::Words(
+            (*min..=*max).map(|number| number.to_string()).collect(),
+        )),
+        ArgKind::Choice(choices) => Some(Values::Words(
+            choices.iter().map(|choice| choice.to_string()).collect(),
+        )),
+        ArgKind::Colour | ArgKind::Colours => Some(Values::Colours),
+        ArgKind::File => Some(Values::Files),
+        ArgKind::Profile => Some(Values::Profiles),
+        ArgKind::Command => Some(Values::Commands),
+        ArgKind::Number { .. } | ArgKind::Word => None,
+    }
+}
+
+// The values for each argument, by position, and for any after the last one
+fn positions(spec: &CommandSpec) -> (Vec<(usize, Option<Values>)>, Option<Values>) {
+    let mut fixed = vec![];
+    let mut rest = None;
+
+    for (index, arg) in spec.args.iter().enumerate() {
+        if arg.kind.is_rest() {
+            rest = values(&arg.kind);
+        } else {
+            fixed.push((index + 1, values(&arg.kind)));
+        }
+    }
+
+    (fixed, rest)
+}
+
+// Nothing to complete for any of the command's arguments
+fn nothing_to_offer(fixed: &[(usize, Option<Values>)], rest: &Option<Values>) -> bool {
+    rest.is_none() && fixed.iter().all(|(_, values)| values.is_none())
+}
+
+fn commands_words() -> Vec<String> {
+    COMMANDS
+        .iter()
+        .flat_map(|spec| [spec.name].into_iter().chain(spec.aliases.iter().copied()))
+        .map(|word| word.to_string())
+        .collect()
+}
+
+// Every X11 colour, as written and with underscores for the spaces
+pub fn colour_words() -> Vec<String> {
+    let mut words: Vec<String> = vec![];
+
+    for name in x11_colour_names() {
+        for word in [name.clone(), name.replace(' ', "_")] {
+            if !words.contains(&word) {
+                words.push(word);
+            }
+        }
+    }
+
+    words.push("random".to_string());
+
+    words
+}
+
+// Each option once, and whether its value is a file - None for no value
+fn options() -> Vec<(&'static str, Option<bool>, &'static str)> {
+    let mut options: Vec<(&str, Option<bool>, &str)> = vec![];
+
+    for (usage, details) in OPTION_HELP {
+        let mut words = usage.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let value = words.next().map(|value| value == "file");
+
+        if !options.iter().any(|option| option.0 == name) {
+            options.push((name, value, details));
+        }
+    }
+
+    options
+}
+
+fn option_names(with_value: bool) -> Vec<&'static str> {
+    options()
+        .into_iter()
+        .filter(|(_, value, _)| !with_value || value.is_some())
+        .map(|(name, _, _)| name)
+        .collect()
+}
+
+fn file_options() -> Vec<&'static str> {
+    options()
+        .into_iter()
+        .filter(|(_, value, _)| *value == Some(true))
+        .map(|(name, _, _)| name)
+        .collect()
+}
+
+// A single quoted shell word
+fn quote(word: &str) -> String {
+    if word
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+    {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+// A newline separated list, as a bash/zsh $'...' string
+fn sh_lines(words: &[String]) -> String {
+    let escaped: Vec<_> = words
+        .iter()
+        .map(|word| word.replace('\\', r"\\").replace('\'', r"\'"))
+        .collect();
+
+    format!("$'{}'", escaped.join(r"\n"))
+}
+
+// The case statements shared by bash and zsh, `first` and `count` are how
+// each shell spells the command and the number of arguments so far
+fn sh_cases(first: &str, count: &str, offer: fn(&Values) -> String) -> String {
+    let mut text = format!("        case {} in\n", first);
+
+    for spec in COMMANDS.iter() {
+        let (fixed, rest) = positions(spec);
+
+        if nothing_to_offer(&fixed, &rest) {
+            continue;
+        }
+
+        let patterns: Vec<_> = [spec.name]
+            .iter()
+            .chain(spec.aliases.iter())
+            .map(|word| quote(word))
+            .collect();
+
+        text += &format!("            {})\n", patterns.join("|"));
+        text += &format!("                case {} in\n", count);
+
+        for (position, values) in &fixed {
+            let offer = values
+                .as_ref()
+                .map_or(String::new(), |values| offer(values) + " ");
+
+            text += &format!("                    {}) {};;\n", position, offer);
+        }
+        if let Some(values) = &rest {
+            text += &format!("                    *) {} ;;\n", offer(values));
+        }
+
+        text += "                esac\n                ;;\n";
+    }
+
+    text + "        esac\n"
+}
+
+fn sh_colours_function() -> String {
+    format!(
+        "_g213_cols_colours() {{\n    cat <<'COLOURS'\n{}\nCOLOURS\n}}\n",
+        colour_words().join("\n")
+    )
+}
+
+fn bash_offer(values: &Values) -> String {
+    match values {
+        Values::Commands => format!("_g213_cols_offer {}", sh_lines(&commands_words())),
+        Values::Words(words) => format!("_g213_cols_offer {}", sh_lines(words)),
+        Values::Colours => "_g213_cols_offer \"$(_g213_cols_colours)\"".to_string(),
+        Values::Profiles => "_g213_cols_offer \"$(_g213_cols_profiles)\"".to_string(),
+        Values::Files => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+    }
+}
+
+fn bash() -> String {
+    format!(
+        r#"# bash completion for g213-cols
+# source <(g213-cols completions bash)
+
+{colours}
+_g213_cols_profiles() {{
+    "${{COMP_WORDS[0]}}" profile list 2>/dev/null | sed 's/ (system)$//'
+}}
+
+_g213_cols_offer() {{
+    local IFS=$'\n'
+
+    COMPREPLY=($(compgen -W "$1" -- "$cur"))
+
+    if ((${{#COMPREPLY[@]}})); then
+        COMPREPLY=($(printf '%q\n' "${{COMPREPLY[@]}}"))
+    fi
+}}
+
+_g213_cols() {{
+    local cur=${{COMP_WORDS[COMP_CWORD]}} word option= i
+    local -a args=()
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word=${{COMP_WORDS[i]}}
+
+        if [[ -n $option ]]; then
+            option=
+        elif [[ $word == --* ]]; then
+            case $word in
+                {value_options}) option=$word ;;
+            esac
+        else
+            args+=("$word")
+        fi
+    done
+
+    if [[ -n $option ]]; then
+        case $option in
+            {file_options}) COMPREPLY=($(compgen -f -- "$cur")) ;;
+        esac
+    elif [[ $cur == --* ]]; then
+        _g213_cols_offer {options}
+    elif ((${{#args[@]}} == 0)); then
+        {commands}
+    else
+{cases}    fi
+}}
+
+complete -F _g213_cols g213-cols
+"#,
+        colours = sh_colours_function(),
+        value_options = option_names(true).join("|"),
+        file_options = file_options().join("|"),
+        options = sh_lines(
+            &option_names(false)
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        ),
+        commands = bash_offer(&Values::Commands),
+        cases = sh_cases("${args[0]}", "${#args[@]}", bash_offer),
+    )
+}
+
+fn zsh_offer(values: &Values) -> String {
+    match values {
+        Values::Files => "_files".to_string(),
+        values => bash_offer(values),
+    }
+}
+
+fn zsh() -> String {
+    format!(
+        r#"#compdef g213-cols
+# zsh completion for g213-cols
+# source <(g213-cols completions zsh), or save as _g213-cols in your $fpath
+
+{colours}
+_g213_cols_profiles() {{
+    "${{words[1]}}" profile list 2>/dev/null | sed 's/ (system)$//'
+}}
+
+_g213_cols_offer() {{
+    local -a candidates
+
+    candidates=("${{(@f)1}}")
+    compadd -a candidates
+}}
+
+_g213-cols() {{
+    local word option= i
+    local -a args
+
+    for ((i = 2; i < CURRENT; i++)); do
+        word=${{words[i]}}
+
+        if [[ -n $option ]]; then
+            option=
+        elif [[ $word == --* ]]; then
+            case $word in
+                {value_options}) option=$word ;;
+            esac
+        else
+            args+=("$word")
+        fi
+    done
+
+    if [[ -n $option ]]; then
+        case $option in
+            {file_options}) _files ;;
+        esac
+    elif [[ $PREFIX == --* ]]; then
+        _g213_cols_offer {options}
+    elif (( ${{#args}} == 0 )); then
+        {commands}
+    else
+{cases}    fi
+}}
+
+if [[ $zsh_eval_context[-1] == loadautofunc ]]; then
+    _g213-cols "$@"
+else
+    compdef _g213-cols g213-cols
+fi
+"#,
+        colours = sh_colours_function(),
+        value_options = option_names(true).join("|"),
+        file_options = file_options().join("|"),
+        options = sh_lines(
+            &option_names(false)
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        ),
+        commands = zsh_offer(&Values::Commands),
+        cases = sh_cases("${args[1]}", "${#args}", zsh_offer),
+    )
+}
+
+fn fish_words(words: &[String]) -> String {
+    let quoted: Vec<_> = words.iter().map(|word| quote(word)).collect();
+
+    format!("printf '%s\\n' {}", quoted.join(" "))
+}
+
+fn fish_offer(values: &Values) -> String {
+    match values {
+        Values::Commands => fish_words(&commands_words()),
+        Values::Words(words) => fish_words(words),
+        Values::Colours => "__g213_cols_colours".to_string(),
+        Values::Profiles => "__g213_cols_profiles".to_string(),
+        Values::Files => "__fish_complete_path (commandline -ct)".to_string(),
+    }
+}
+
+// `if ... else if ... end` over the commands, `contains` as `switch` would
+// treat the '?' alias as a wildcard
+fn fish_branches() -> String {
+    let mut text = String::new();
+
+    for spec in COMMANDS.iter() {
+        let (fixed, rest) = positions(spec);
+
+        if nothing_to_offer(&fixed, &rest) {
+            continue;
+        }
+
+        let names: Vec<_> = [spec.name]
+            .iter()
+            .chain(spec.aliases.iter())
+            .map(|word| quote(word))
+            .collect();
+
+        let keyword = if text.is_empty() { "if" } else { "else if" };
+        text += &format!("    {} contains -- $args[1] {}\n", keyword, names.join(" "));
+
+        for (index, (position, values)) in fixed.iter().enumerate() {
+            let keyword = if index == 0 { "if" } else { "else if" };
+
+            text += &format!("        {} test $count -eq {}\n", keyword, position);
+
+            if let Some(values) = values {
+                text += &format!("            {}\n", fish_offer(values));
+            }
+        }
+
+        match (&rest, fixed.is_empty()) {
+            (Some(values), true) => text += &format!("        {}\n", fish_offer(values)),
+            (Some(values), false) => {
+                text += &format!(
+                    "        else\n            {}\n        end\n",
+                    fish_offer(values)
+                )
+            }
+            (None, _) => text += "        end\n",
+        }
+    }
+
+    text + "    end\n"
+}
+
+fn fish() -> String {
+    let colours: Vec<_> = colour_words()
+        .iter()
+        .map(|word| format!("        {}", quote(word)))
+        .collect();
+
+    let options: Vec<_> = options()
+        .into_iter()
+        .map(|(name, value, details)| {
+            let value = match value {
+                Some(true) => " -r -F",
+                Some(false) => " -x",
+                None => "",
+            };
+
+            format!(
+                "complete -c g213-cols -l {}{} -d {}",
+                name.trim_start_matches("--"),
+                value,
+                quote(details)
+            )
+        })
+        .collect();
+
+    format!(
+        r#"# fish completion for g213-cols
+# g213-cols completions fish > ~/.config/fish/completions/g213-cols.fish
+
+function __g213_cols_colours
+    printf '%s\n' \
+{colours}
+end
+
+function __g213_cols_profiles
+    set -l cmd (commandline -opc)[1]
+
+    $cmd profile list 2>/dev/null | string replace -r ' \(system\)$' ''
+end
+
+function __g213_cols_complete
+    set -l args
+    set -l option
+
+    for word in (commandline -opc)[2..-1]
+        if test -n "$option"
+            set option
+        else if string match -q -- '--*' $word
+            contains -- $word {value_options}; and set option $word
+        else
+            set -a args $word
+        end
+    end
+
+    # The option's own completion takes care of its value
+    test -n "$option"; and return
+
+    set -l count (count $args)
+
+    if test $count -eq 0
+        {commands}
+        return
+    end
+
+{branches}end
+
+complete -c g213-cols -f -a '(__g213_cols_complete)'
+{options}
+"#,
+        colours = colours.join(" \\\n"),
+        value_options = option_names(true).join(" "),
+        commands = fish_offer(&Values::Commands),
+        branches = fish_branches(),
+        options = options.join("\n"),
+    )
+}
+
+// The completion script for a shell, if we know it
+pub fn completions(shell: &str) -> Option<String> {
+    match shell.to_lowercase().as_str() {
+        "bash" => Some(bash()),
+        "zsh" => Some(zsh()),
+        "fish" => Some(fish()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod completions_tests {
+    use super::*;
+
+    #[test]
+    fn colours_with_spaces_and_underscores() {
+        let words = colour_words();
+
+        assert!(words.contains(&"lawn green".to_string()));
+        assert!(words.contains(&"lawn_green".to_string()));
+        assert!(words.contains(&"lawngreen".to_string()));
+        assert!(words.contains(&"random".to_string()));
+        assert_eq!(words.iter().filter(|word| *word == "snow").count(), 1);
+    }
+
+    #[test]
+    fn options_come_from_the_help() {
+        assert_eq!(
+            options()[0],
+            (
+                "--device",
+                Some(false),
+                "use the keyboard at USB bus and address, eg 1:5"
+            )
+        );
+        assert_eq!(
+            option_names(true),
+            vec!["--device", "--capture", "--config"]
+        );
+        assert_eq!(file_options(), vec!["--capture", "--config"]);
+    }
+
+    #[test]
+    fn region_numbers_but_not_speeds() {
+        let (fixed, rest) = positions(crate::registry::find_command("region").unwrap());
+
+        assert!(matches!(
+            &fixed[..],
+            [(1, Some(Values::Words(words)))] if words == &["1", "2", "3", "4", "5"]
+        ));
+        assert!(matches!(rest, Some(Values::Colours)));
+
+        let (fixed, _) = positions(crate::registry::find_command("breathe").unwrap());
+
+        assert!(matches!(&fixed[..], [(1, None)]));
+    }
+
+    #[test]
+    fn scripts_cover_commands_and_profiles() {
+        for shell in ["bash", "zsh", "fish"] {
+            let script = completions(shell).unwrap();
+
+            for word in [
+                "colour",
+                "cy",
+                "setup-permissions",
+                "'?'",
+                "dark_slate_blue",
+            ] {
+                assert!(script.contains(word), "{} script has no {}", shell, word);
+            }
+            assert!(script.contains("profile list"));
+        }
+
+        assert!(completions("tcsh").is_none());
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("colour"), "colour");
+        assert_eq!(quote("?"), "'?'");
+        assert_eq!(quote("alice blue"), "'alice blue'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,649 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{fchown, lchown, DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use dirs::{config_dir, home_dir};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::commands::Command;
+use crate::error::G213Error;
+use crate::state::LightingState;
+
+// Config lives in $XDG_CONFIG_HOME/g213-cols (usually ~/.config/g213-cols),
+// with machine wide defaults in /etc/g213-cols
+const APP_DIR: &str = "g213-cols";
+const SYSTEM_CONFIG_DIR: &str = "/etc/g213-cols";
+const CONFIG_FILE: &str = "config.json";
+const PROFILES_FILE: &str = "profiles.json";
+
+// Where things were kept before, in the home directory
+const LEGACY_CONFIG_FILE: &str = ".g213-cols.json";
+const LEGACY_PROFILES_FILE: &str = ".g213-cols-profiles.json";
+
+lazy_static! {
+    // Set by --config
+    static ref EXPLICIT_CONFIG_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigPaths {
+    pub explicit: Option<PathBuf>,
+    pub user_dir: Option<PathBuf>,
+    pub system_dir: PathBuf,
+}
+
+impl ConfigPaths {
+    // Where the saved command is written - --config if given, otherwise the user's
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.explicit
+            .clone()
+            .or_else(|| Some(self.user_dir.as_ref()?.join(CONFIG_FILE)))
+    }
+
+    // Where the saved command is looked for, first match wins
+    pub fn config_layers(&self) -> Vec<PathBuf> {
+        self.config_file()
+            .into_iter()
+            .chain([self.system_dir.join(CONFIG_FILE)])
+            .collect()
+    }
+
+    pub fn profiles_file(&self) -> Option<PathBuf> {
+        Some(self.user_dir.as_ref()?.join(PROFILES_FILE))
+    }
+
+    pub fn system_profiles_file(&self) -> PathBuf {
+        self.system_dir.join(PROFILES_FILE)
+    }
+}
+
+pub fn set_config_file(path: Option<PathBuf>) {
+    *EXPLICIT_CONFIG_FILE.lock().unwrap() = path;
+}
+
+pub fn config_paths() -> ConfigPaths {
+    ConfigPaths {
+        explicit: EXPLICIT_CONFIG_FILE.lock().unwrap().clone(),
+        user_dir: config_dir().map(|dir| dir.join(APP_DIR)),
+        system_dir: PathBuf::from(SYSTEM_CONFIG_DIR),
+    }
+}
+
+// Who should own files we create. Under sudo we are really root, but the
+// files belong to whoever ran sudo.
+fn sudo_owner() -> Option<(u32, u32)> {
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+
+    let uid = std::env::var("SUDO_UID").ok()?.parse().ok()?;
+    let gid = std::env::var("SUDO_GID").ok()?.parse().ok()?;
+
+    Some((uid, gid))
+}
+
+fn temp_file_path(path: &Path) -> Result<PathBuf, G213Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| G213Error::Io(format!("Not a file: {}", path.display())))?;
+
+    Ok(path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    )))
+}
+
+fn write_new_file(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> io::Result<()> {
+    // create_new won't follow a symlink, O_NOFOLLOW makes sure of it
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+
+    file.write_all(contents)?;
+
+    if let Some((uid, gid)) = owner {
+        fchown(&file, Some(uid), Some(gid))?;
+    }
+
+    file.sync_all()
+}
+
+// Creates `dir` and any missing parents one at a time, so that under sudo
+// each one we made can be handed back, not just the last
+fn create_config_dir(dir: &Path) -> io::Result<()> {
+    if dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+
+    let created = match DirBuilder::new().mode(0o700).create(dir) {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            if let Some(parent) = dir.parent() {
+                create_config_dir(parent)?;
+            }
+
+            DirBuilder::new().mode(0o700).create(dir)
+        }
+        result => result,
+    };
+
+    match created {
+        Ok(()) => (),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => return Ok(()),
+        Err(err) => return Err(err),
+    }
+
+    if let Some((uid, gid)) = sudo_owner() {
+        lchown(dir, Some(uid), Some(gid))?;
+    }
+
+    Ok(())
+}
+
+// Replaces `path` with `contents` in one go, via a temp file in the same
+// directory. Refuses to write through a symlink.
+pub fn replace_file(
+    path: &Path,
+    contents: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> Result<(), G213Error> {
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+        return Err(G213Error::Io(format!(
+            "Refusing to write through symlink {}",
+            path.display()
+        )));
+    }
+
+    let temp = temp_file_path(path)?;
+
+    // rename replaces a symlink that turns up meanwhile, not what it points to
+    let result = write_new_file(&temp, contents, mode, owner).and_then(|_| fs::rename(&temp, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result.map_err(|err| G213Error::file("write", path, err))
+}
+
+// As `replace_file`, creating the directory if need be. Under sudo the
+// files belong to whoever ran it.
+pub fn write_config_file(path: &Path, contents: &[u8]) -> Result<(), G213Error> {
+    if let Some(dir) = path.parent() {
+        create_config_dir(dir).map_err(|err| G213Error::file("create", dir, err))?;
+    }
+
+    replace_file(path, contents, 0o600, sudo_owner())
+}
+
+// The saved file is versioned, so that older files can be migrated:
+//
+//   0 - a bare `Command`, from before there was a version
+//   1 - `{ "version": 1, "command": Command }`
+//   2 - adds `"state": LightingState`, or null if it isn't known
+pub const CONFIG_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedConfig {
+    pub version: u32,
+    pub command: Command,
+    pub state: Option<LightingState>,
+}
+
+#[derive(Debug, PartialEq)]
+enum LoadError {
+    Corrupt(String),
+    Newer(u32),
+}
+
+fn file_version(value: &Value) -> Result<u32, LoadError> {
+    match value.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .map(|version| version as u32)
+            .ok_or_else(|| LoadError::Corrupt(format!("bad version: {}", version))),
+    }
+}
+
+// Brings a file from `version` up to the next one
+fn migrate(value: Value, version: u32) -> Value {
+    match version {
+        0 => json!({ "version": 1, "command": value }),
+        1 => {
+            let mut value = value;
+            value["version"] = json!(2);
+            value["state"] = Value::Null;
+            value
+        }
+        _ => value,
+    }
+}
+
+fn parse_saved_config(text: &str) -> Result<SavedConfig, LoadError> {
+    let corrupt = |err: serde_json::Error| LoadError::Corrupt(err.to_string());
+
+    let mut value: Value = serde_json::from_str(text).map_err(corrupt)?;
+    let mut version = file_version(&value)?;
+
+    if version > CONFIG_VERSION {
+        return Err(LoadError::Newer(version));
+    }
+
+    while version < CONFIG_VERSION {
+        value = migrate(value, version);
+        version += 1;
+    }
+
+    serde_json::from_value(value).map_err(corrupt)
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+
+    PathBuf::from(name)
+}
+
+// The saved command in `path`, if there is a usable one. A file we can't
+// make sense of is moved out of the way rather than stopping every run, but
+// one we can't read is left where it is.
+pub fn read_saved_config(path: &Path) -> Option<SavedConfig> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => Ok(text),
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            Err(LoadError::Corrupt(err.to_string()))
+        }
+        Err(err) => {
+            eprintln!("{}", G213Error::file("read", path, err));
+            return None;
+        }
+    };
+
+    match text.and_then(|text| parse_saved_config(&text)) {
+        Ok(config) => Some(config),
+        Err(LoadError::Newer(version)) => {
+            eprintln!(
+                "Ignoring saved command in {}, it is from a newer g213-cols (version {})",
+                path.display(),
+                version
+            );
+            None
+        }
+        Err(LoadError::Corrupt(msg)) => {
+            let backup = backup_path(path);
+
+            match fs::rename(path, &backup) {
+                Ok(()) => eprintln!(
+                    "Unable to use saved command in {} ({}), moved it to {}",
+                    path.display(),
+                    msg,
+                    backup.display()
+                ),
+                Err(err) => eprintln!(
+                    "Unable to use saved command in {} ({}), and unable to back it up: {}",
+                    path.display(),
+                    msg,
+                    err
+                ),
+            }
+            None
+        }
+    }
+}
+
+// The first usable saved command, in precedence order
+pub fn read_layered_config(paths: &ConfigPaths) -> Option<SavedConfig> {
+    paths
+        .config_layers()
+        .iter()
+        .find_map(|path| read_saved_config(path))
+}
+
+pub fn get_saved_config() -> Option<SavedConfig> {
+    read_layered_config(&config_paths())
+}
+
+pub fn get_saved_command() -> Option<Command> {
+    get_saved_config().map(|config| config.command)
+}
+
+pub fn save_command(command: &Command, state: Option<LightingState>) -> Result<(), G213Error> {
+    let config = SavedConfig {
+        version: CONFIG_VERSION,
+        command: command.clone(),
+        state,
+    };
+    let ser_config = serde_json::to_string(&config).unwrap();
+    let path = config_paths()
+        .config_file()
+        .ok_or_else(|| G213Error::Io("Unable to find a config directory".to_string()))?;
+
+    write_config_file(&path, ser_config.as_bytes())
+}
+
+// Moves a file from where older versions kept it, unless there already is one
+fn migrate_file(old: &Path, new: &Path) -> Result<bool, G213Error> {
+    let is_file = fs::symlink_metadata(old).is_ok_and(|meta| meta.file_type().is_file());
+
+    if !is_file || fs::symlink_metadata(new).is_ok() {
+        return Ok(false);
+    }
+
+    let contents = fs::read(old).map_err(|err| G213Error::file("read", old, err))?;
+
+    write_config_file(new, &contents)?;
+    fs::remove_file(old).map_err(|err| G213Error::file("remove", old, err))?;
+
+    Ok(true)
+}
+
+// ~/.g213-cols.json and friends move to the user config directory
+pub fn migrate_legacy_files() {
+    let (Some(home), Some(user_dir)) = (home_dir(), config_paths().user_dir) else {
+        return;
+    };
+
+    for (old, new) in [
+        (LEGACY_CONFIG_FILE, CONFIG_FILE),
+        (LEGACY_PROFILES_FILE, PROFILES_FILE),
+    ] {
+        let (old, new) = (home.join(old), user_dir.join(new));
+
+        match migrate_file(&old, &new) {
+            Ok(true) => eprintln!("Moved {} to {}", old.display(), new.display()),
+            Ok(false) => (),
+            Err(err) => eprintln!("Unable to move {}: {}", old.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::packet::Packet;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("g213-config-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn writes_and_replaces_file() {
+        let dir = temp_dir("replace");
+        let path = dir.join("config.json");
+
+        write_config_file(&path, b"first").unwrap();
+        write_config_file(&path, b"second").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_write_through_symlink() {
+        let dir = temp_dir("symlink");
+        let target = dir.join("target");
+        let path = dir.join("config.json");
+
+        fs::write(&target, "precious").unwrap();
+        symlink(&target, &path).unwrap();
+
+        assert!(write_config_file(&path, b"oops").is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "precious");
+        assert!(!temp_file_path(&path).unwrap().exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn layered_paths(dir: &Path, explicit: Option<&str>) -> ConfigPaths {
+        ConfigPaths {
+            explicit: explicit.map(|name| dir.join(name)),
+            user_dir: Some(dir.join("user")),
+            system_dir: dir.join("system"),
+        }
+    }
+
+    fn write_saved(path: &Path, colour: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            path,
+            format!(
+                r#"{{"version":2,"command":{{"Colour":["{}"]}},"state":null}}"#,
+                colour
+            ),
+        )
+        .unwrap();
+    }
+
+    fn layered_colour(paths: &ConfigPaths) -> Option<Vec<String>> {
+        match read_layered_config(paths)?.command {
+            Command::Colour(args) => Some(args),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn config_layers_precedence() {
+        let dir = temp_dir("layers");
+        let paths = layered_paths(&dir, None);
+
+        assert_eq!(layered_colour(&paths), None);
+
+        write_saved(&dir.join("system/config.json"), "blue");
+        assert_eq!(layered_colour(&paths), Some(vec!["blue".to_string()]));
+
+        write_saved(&dir.join("user/config.json"), "red");
+        assert_eq!(layered_colour(&paths), Some(vec!["red".to_string()]));
+
+        write_saved(&dir.join("explicit.json"), "green");
+        let explicit = layered_paths(&dir, Some("explicit.json"));
+        assert_eq!(layered_colour(&explicit), Some(vec!["green".to_string()]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn explicit_config_replaces_user_layer() {
+        let dir = temp_dir("explicit");
+        let paths = layered_paths(&dir, Some("missing.json"));
+
+        write_saved(&dir.join("user/config.json"), "red");
+        write_saved(&dir.join("system/config.json"), "blue");
+
+        assert_eq!(paths.config_file(), Some(dir.join("missing.json")));
+        assert_eq!(layered_colour(&paths), Some(vec!["blue".to_string()]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn no_user_dir_still_reads_system() {
+        let dir = temp_dir("no-user");
+        let paths = ConfigPaths {
+            user_dir: None,
+            ..layered_paths(&dir, None)
+        };
+
+        write_saved(&dir.join("system/config.json"), "blue");
+
+        assert_eq!(paths.config_file(), None);
+        assert_eq!(paths.profiles_file(), None);
+        assert_eq!(layered_colour(&paths), Some(vec!["blue".to_string()]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_file_is_migrated() {
+        let dir = temp_dir("migrate");
+        let old = dir.join(LEGACY_CONFIG_FILE);
+        let new = dir.join("user/g213-cols").join(CONFIG_FILE);
+
+        fs::write(&old, r#"{"Colour":["red"]}"#).unwrap();
+
+        assert_eq!(migrate_file(&old, &new), Ok(true));
+        assert!(!old.exists());
+        assert_eq!(fs::read_to_string(&new).unwrap(), r#"{"Colour":["red"]}"#);
+
+        // Nothing left to move, and an existing file is never replaced
+        fs::write(&old, "older").unwrap();
+
+        assert_eq!(migrate_file(&old, &new), Ok(false));
+        assert_eq!(fs::read_to_string(&new).unwrap(), r#"{"Colour":["red"]}"#);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_symlink_is_not_migrated() {
+        let dir = temp_dir("migrate-symlink");
+        let old = dir.join(LEGACY_CONFIG_FILE);
+        let new = dir.join("user").join(CONFIG_FILE);
+
+        fs::write(dir.join("target"), "precious").unwrap();
+        symlink(dir.join("target"), &old).unwrap();
+
+        assert_eq!(migrate_file(&old, &new), Ok(false));
+        assert!(!new.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn current_version_is_read() {
+        let config = parse_saved_config(
+            r#"{"version":2,"command":{"Cycle":["1000"]},"state":{"packets":[{"Cycle":{"speed":1000,"brightness":100}}]}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert!(matches!(config.command, Command::Cycle(args) if args == ["1000"]));
+        assert_eq!(
+            config.state.unwrap().packets,
+            vec![Packet::Cycle {
+                speed: 1000,
+                brightness: 100
+            }]
+        );
+    }
+
+    #[test]
+    fn version_1_has_no_state() {
+        let config = parse_saved_config(r#"{"version":1,"command":{"Cycle":["1000"]}}"#).unwrap();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert!(matches!(config.command, Command::Cycle(_)));
+        assert!(config.state.is_none());
+    }
+
+    #[test]
+    fn unversioned_command_is_migrated() {
+        let config = parse_saved_config(r#"{"Colour":["lawn","green"]}"#).unwrap();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert!(matches!(config.command, Command::Colour(args) if args == ["lawn", "green"]));
+
+        let config = parse_saved_config(r#""Info""#).unwrap();
+
+        assert!(matches!(config.command, Command::Info));
+    }
+
+    #[test]
+    fn newer_version_is_not_used() {
+        assert_eq!(
+            parse_saved_config(r#"{"version":99,"command":"Info"}"#).unwrap_err(),
+            LoadError::Newer(99)
+        );
+    }
+
+    #[test]
+    fn missing_parents_are_created() {
+        let dir = temp_dir("parents");
+        let nested = dir.join("a").join("b");
+
+        assert!(create_config_dir(&nested).is_ok());
+        assert!(nested.is_dir());
+        assert!(create_config_dir(&nested).is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_file_is_left_alone() {
+        let dir = temp_dir("unreadable");
+        let path = dir.join("config.json");
+
+        // Reading a directory fails, even as root
+        fs::create_dir(&path).unwrap();
+
+        assert!(read_saved_config(&path).is_none());
+        assert!(path.is_dir());
+        assert!(!dir.join("config.json.bak").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_file_is_backed_up() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join("config.json");
+
+        fs::write(&path, r#"{"Colour":["re"#).unwrap();
+
+        assert!(read_saved_config(&path).is_none());
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("config.json.bak")).unwrap(),
+            r#"{"Colour":["re"#
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_not_an_error() {
+        let dir = temp_dir("missing");
+
+        assert!(read_saved_config(&dir.join("config.json")).is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_temp_file_is_not_followed() {
+        let dir = temp_dir("stale");
+        let target = dir.join("target");
+        let path = dir.join("config.json");
+
+        fs::write(&target, "precious").unwrap();
+        symlink(&target, temp_file_path(&path).unwrap()).unwrap();
+
+        assert!(write_config_file(&path, b"oops").is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "precious");
+        assert!(!path.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
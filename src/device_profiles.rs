@@ -0,0 +1,129 @@
+use crate::error::G213Error;
+use crate::g213_keyboard::{G213, LOGITECH, NUM_REGIONS};
+use crate::packet::{Effect, Packet, PacketTemplate, G213_TEMPLATE};
+
+// What we know about each supported Logitech RGB device
+#[derive(Debug, PartialEq)]
+pub struct DeviceProfile {
+    pub name: &'static str,
+    pub product_ids: &'static [u16],
+    pub num_regions: u8,
+    pub interface: u8,
+    pub effects: &'static [Effect],
+    pub template: PacketTemplate,
+}
+
+pub const G213_PROFILE: DeviceProfile = DeviceProfile {
+    name: "G213",
+    product_ids: &[G213],
+    num_regions: NUM_REGIONS,
+    interface: 1,
+    effects: &[Effect::Fixed, Effect::Breathe, Effect::Cycle],
+    template: G213_TEMPLATE,
+};
+
+pub const G203_PROFILE: DeviceProfile = DeviceProfile {
+    name: "G203",
+    product_ids: &[0xc084],
+    num_regions: 1,
+    interface: 1,
+    effects: &[Effect::Fixed, Effect::Breathe, Effect::Cycle],
+    template: PacketTemplate {
+        feature_index: 0x0e,
+        set_effect: 0x3c,
+    },
+};
+
+pub const G413_PROFILE: DeviceProfile = DeviceProfile {
+    name: "G413",
+    product_ids: &[0xc33a],
+    num_regions: 1,
+    interface: 1,
+    effects: &[Effect::Fixed, Effect::Breathe],
+    template: G213_TEMPLATE,
+};
+
+pub const DEVICE_PROFILES: [&DeviceProfile; 3] = [&G213_PROFILE, &G203_PROFILE, &G413_PROFILE];
+
+pub fn find_profile(vendor_id: u16, product_id: u16) -> Option<&'static DeviceProfile> {
+    if vendor_id != LOGITECH {
+        return None;
+    }
+
+    DEVICE_PROFILES
+        .into_iter()
+        .find(|profile| profile.product_ids.contains(&product_id))
+}
+
+impl DeviceProfile {
+    pub fn check_region(&self, region: u8) -> Result<(), G213Error> {
+        if region <= self.num_regions {
+            Ok(())
+        } else {
+            Err(G213Error::Unsupported(format!(
+                "{} has regions 1 to {}, not {}",
+                self.name, self.num_regions, region
+            )))
+        }
+    }
+
+    pub fn check_packet(&self, packet: &Packet) -> Result<(), G213Error> {
+        if let Packet::Colour { region, .. } = packet {
+            self.check_region(*region)?;
+        }
+
+        if self.effects.contains(&packet.effect()) {
+            Ok(())
+        } else {
+            Err(G213Error::Unsupported(format!(
+                "{} does not support the {:?} effect",
+                self.name,
+                packet.effect()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod device_profiles_tests {
+    use super::*;
+    use crate::packet::DEFAULT_BRIGHTNESS;
+
+    #[test]
+    fn find_known_profiles() {
+        assert_eq!(find_profile(LOGITECH, G213), Some(&G213_PROFILE));
+        assert_eq!(find_profile(LOGITECH, 0xc084), Some(&G203_PROFILE));
+        assert_eq!(find_profile(LOGITECH, 0xc33a), Some(&G413_PROFILE));
+    }
+
+    #[test]
+    fn unknown_devices_have_no_profile() {
+        assert_eq!(find_profile(LOGITECH, 0x1234), None);
+        assert_eq!(find_profile(0x0400, G213), None);
+    }
+
+    #[test]
+    fn regions_are_checked() {
+        let packet = Packet::Colour {
+            region: 3,
+            colour: 0xff0000,
+        };
+
+        assert_eq!(G213_PROFILE.check_packet(&packet), Ok(()));
+        assert!(G413_PROFILE.check_packet(&packet).is_err());
+    }
+
+    #[test]
+    fn effects_are_checked() {
+        let packet = Packet::Cycle {
+            speed: 1000,
+            brightness: DEFAULT_BRIGHTNESS,
+        };
+
+        assert_eq!(G203_PROFILE.check_packet(&packet), Ok(()));
+        assert!(matches!(
+            G413_PROFILE.check_packet(&packet),
+            Err(G213Error::Unsupported(_))
+        ));
+    }
+}
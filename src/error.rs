@@ -0,0 +1,169 @@
+use std::fmt::Display;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum G213Error {
+    NotFound,
+    PermissionDenied,
+    Busy,
+    Timeout,
+    Protocol(String),
+    Unsupported(String),
+    Rejected {
+        feature_index: u8,
+        function: u8,
+        code: u8,
+    },
+    DriverReattach(rusb::Error),
+    NoUsb(rusb::Error),
+    Usb(rusb::Error),
+    Io(String),
+}
+
+impl G213Error {
+    // Errors that mean there is no keyboard to talk to, rather than a problem with one
+    pub fn is_no_device(&self) -> bool {
+        matches!(self, G213Error::NotFound | G213Error::NoUsb(_))
+    }
+
+    // For config and other files - unlike `From<io::Error>`, which is for the
+    // keyboard, this names the file and keeps the error as it is
+    pub fn file(action: &str, path: &Path, err: std::io::Error) -> Self {
+        G213Error::Io(format!("Unable to {} {}: {}", action, path.display(), err))
+    }
+}
+
+// HID++ 2.0 error codes
+fn hidpp_error_name(code: u8) -> String {
+    match code {
+        0x01 => "unknown".to_string(),
+        0x02 => "invalid argument".to_string(),
+        0x03 => "out of range".to_string(),
+        0x04 => "hardware error".to_string(),
+        0x05 => "internal error".to_string(),
+        0x06 => "invalid feature index".to_string(),
+        0x07 => "invalid function".to_string(),
+        0x08 => "busy".to_string(),
+        0x09 => "unsupported".to_string(),
+        _ => format!("error {:#04x}", code),
+    }
+}
+
+impl Display for G213Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            G213Error::NotFound => write!(f, "No G213 keyboard found, sorry!"),
+            G213Error::PermissionDenied => {
+                write!(
+                    f,
+                    "Permission denied accessing the keyboard - are you root?"
+                )
+            }
+            G213Error::Busy => write!(f, "Keyboard is busy - is another program using it?"),
+            G213Error::Timeout => write!(f, "Timed out talking to the keyboard"),
+            G213Error::Protocol(msg) => write!(f, "Keyboard protocol error: {}", msg),
+            G213Error::Unsupported(msg) => write!(f, "{}", msg),
+            G213Error::Rejected {
+                feature_index,
+                function,
+                code,
+            } => write!(
+                f,
+                "Keyboard rejected packet for feature {:#04x} function {:#04x}: {}",
+                feature_index,
+                function,
+                hidpp_error_name(*code)
+            ),
+            G213Error::DriverReattach(err) => write!(
+                f,
+                "Unable to re-attach the kernel USB driver ({}) - replug the keyboard",
+                err
+            ),
+            G213Error::NoUsb(err) => {
+                write!(f, "Unable to start libusb ({}) - is there any USB?", err)
+            }
+            G213Error::Usb(err) => write!(f, "USB error: {}", err),
+            G213Error::Io(msg) => write!(f, "I/O error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for G213Error {}
+
+impl From<rusb::Error> for G213Error {
+    fn from(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::NoDevice | rusb::Error::NotFound => G213Error::NotFound,
+            rusb::Error::Access => G213Error::PermissionDenied,
+            rusb::Error::Busy => G213Error::Busy,
+            rusb::Error::Timeout => G213Error::Timeout,
+            _ => G213Error::Usb(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for G213Error {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => G213Error::NotFound,
+            std::io::ErrorKind::PermissionDenied => G213Error::PermissionDenied,
+            std::io::ErrorKind::TimedOut => G213Error::Timeout,
+            _ => G213Error::Io(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+
+    #[test]
+    fn usb_errors_are_mapped() {
+        assert_eq!(
+            G213Error::from(rusb::Error::Access),
+            G213Error::PermissionDenied
+        );
+        assert_eq!(G213Error::from(rusb::Error::Busy), G213Error::Busy);
+        assert_eq!(G213Error::from(rusb::Error::Timeout), G213Error::Timeout);
+        assert_eq!(G213Error::from(rusb::Error::NoDevice), G213Error::NotFound);
+        assert_eq!(
+            G213Error::from(rusb::Error::Pipe),
+            G213Error::Usb(rusb::Error::Pipe)
+        );
+    }
+
+    #[test]
+    fn io_errors_are_mapped() {
+        use std::io::{Error, ErrorKind};
+
+        assert_eq!(
+            G213Error::from(Error::from(ErrorKind::PermissionDenied)),
+            G213Error::PermissionDenied
+        );
+        assert_eq!(
+            G213Error::from(Error::from(ErrorKind::NotFound)),
+            G213Error::NotFound
+        );
+        assert!(matches!(
+            G213Error::from(Error::from(ErrorKind::BrokenPipe)),
+            G213Error::Io(_)
+        ));
+    }
+
+    #[test]
+    fn file_errors_name_the_file() {
+        use std::io::{Error, ErrorKind};
+
+        let err = G213Error::file(
+            "write",
+            Path::new("/etc/g213-cols/config.json"),
+            Error::from(ErrorKind::NotFound),
+        );
+
+        assert_eq!(
+            err.to_string(),
+            "I/O error: Unable to write /etc/g213-cols/config.json: entity not found"
+        );
+        assert!(!err.is_no_device());
+    }
+}
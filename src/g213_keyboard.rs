@@ -1,6 +1,18 @@
-use rusb::{devices, Device, DeviceDescriptor, DeviceHandle, Error, GlobalContext};
+use rusb::{Context, Device, DeviceDescriptor, DeviceHandle, UsbContext};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
 use std::time::Duration;
 
+use crate::device_profiles::{find_profile, DeviceProfile, G213_PROFILE};
+use crate::error::G213Error;
+use crate::hidraw::{find_hidraw_nodes, HidrawTransport, DEV_ROOT, SYSFS_ROOT};
+use crate::packet::{
+    feature_request, read_long_report, Packet, PacketTemplate, Reply, CMD_LEN, COLOUR_LED_EFFECTS,
+    DEFAULT_BRIGHTNESS,
+};
+use crate::transport::{DeviceInfo, DeviceSelector, InterfaceGuard, Transport};
+
 pub const NUM_REGIONS: u8 = 5;
 
 pub const LOGITECH: u16 = 0x046d; // Vendor
@@ -11,14 +23,25 @@ const ENDPOINT: u8 = 0x82; // Read Interrupt
 const REQ_TYPE: u8 = 0x21;
 const REQ: u8 = 0x09;
 const VALUE: u16 = 0x0211;
-const INDEX: u16 = 0x0001;
-const CMD_LEN: usize = 20;
 const TIMEOUT_MS: u64 = 50;
 
 const MIN_SPEED: u16 = 32;
 
+lazy_static! {
+    // Where each keyboard, by serial number, keeps its colour LED effects feature
+    static ref FEATURE_INDEXES: Mutex<HashMap<String, u8>> = Mutex::new(HashMap::new());
+
+    // libusb can fail to start, eg in a container with no USB. rusb's
+    // Context panics when that happens, so we keep our own.
+    static ref USB_CONTEXT: Result<Context, rusb::Error> = Context::new();
+}
+
+pub fn usb_context() -> Result<Context, G213Error> {
+    USB_CONTEXT.clone().map_err(G213Error::NoUsb)
+}
+
 #[repr(u8)]
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug)]
 pub enum KeyboardRegions {
     WholeKeyboard = 0,
     Region1 = 1,
@@ -28,16 +51,21 @@ pub enum KeyboardRegions {
     Region5 = 5,
 }
 
-impl From<u8> for KeyboardRegions {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for KeyboardRegions {
+    type Error = G213Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Self::WholeKeyboard,
-            1 => Self::Region1,
-            2 => Self::Region2,
-            3 => Self::Region3,
-            4 => Self::Region4,
-            5 => Self::Region5,
-            _ => Self::WholeKeyboard,
+            0 => Ok(Self::WholeKeyboard),
+            1 => Ok(Self::Region1),
+            2 => Ok(Self::Region2),
+            3 => Ok(Self::Region3),
+            4 => Ok(Self::Region4),
+            5 => Ok(Self::Region5),
+            _ => Err(G213Error::Unsupported(format!(
+                "There is no keyboard region {}",
+                value
+            ))),
         }
     }
 }
@@ -69,149 +97,500 @@ pub fn is_g213_keyboard(descriptor: &dyn G213DeviceDescriptor) -> bool {
     descriptor.vendor_id() == LOGITECH && descriptor.product_id() == G213
 }
 
-fn send_to_keyboard(
-    handle: &DeviceHandle<GlobalContext>,
-    bytes: &mut [u8],
-) -> Result<usize, Error> {
-    handle.write_control(
-        REQ_TYPE,
-        REQ,
-        VALUE,
-        INDEX,
-        bytes,
-        Duration::from_millis(TIMEOUT_MS),
-    )?;
+// A G213 or one of its relatives in the device profile table
+pub fn is_supported_device(descriptor: &dyn G213DeviceDescriptor) -> bool {
+    find_profile(descriptor.vendor_id(), descriptor.product_id()).is_some()
+}
+
+fn send_to_keyboard<T: Transport>(transport: &mut T, bytes: &[u8]) -> Result<Reply, G213Error> {
+    transport.write_control(bytes)?;
+
+    let mut reply = [0u8; CMD_LEN];
+    let len = transport.read_interrupt(&mut reply)?;
+
+    let reply = Reply::parse(&reply[..len])?;
+    reply.check_echo(bytes)?;
 
-    handle.read_interrupt(ENDPOINT, bytes, Duration::from_millis(TIMEOUT_MS))
+    Ok(reply)
 }
 
-fn send_command(handle: &DeviceHandle<GlobalContext>, command: &str) -> Result<usize, Error> {
-    let mut bytes = [0u8; CMD_LEN];
+// Asks the keyboard where its colour LED effects feature is, unless we
+// already know for this serial number. Without a serial there is nothing to
+// tell keyboards apart by, so we just ask.
+fn feature_index<T: Transport>(transport: &mut T) -> Result<u8, G213Error> {
+    let serial = transport
+        .info()
+        .ok()
+        .map(|info| info.serial)
+        .filter(|serial| !serial.is_empty());
+
+    if let Some(index) = serial
+        .as_ref()
+        .and_then(|serial| FEATURE_INDEXES.lock().unwrap().get(serial).copied())
+    {
+        return Ok(index);
+    }
+
+    let index = send_to_keyboard(transport, &feature_request(COLOUR_LED_EFFECTS))?
+        .feature_index()
+        .ok_or_else(|| {
+            G213Error::Protocol("keyboard has no colour LED effects feature".to_string())
+        })?;
+
+    if let Some(serial) = serial {
+        FEATURE_INDEXES.lock().unwrap().insert(serial, index);
+    }
 
-    hex::decode_to_slice(command, &mut bytes).unwrap();
+    Ok(index)
+}
 
-    send_to_keyboard(handle, &mut bytes)
+// One claim of the keyboard's interface for any number of packets, instead
+// of a detach/claim/release/attach round trip for each one
+pub struct Session<'a, T: Transport> {
+    guard: InterfaceGuard<&'a mut T>,
+    template: PacketTemplate,
 }
 
-fn send_keyboard_colour(handle: &DeviceHandle<GlobalContext>, region: u8, colour: u32) {
-    let command = format!(
-        "11ff0c3a{:02x}01{:06x}0200000000000000000000",
-        region, colour
-    );
+impl<'a, T: Transport> Session<'a, T> {
+    pub fn start(transport: &'a mut T) -> Result<Self, G213Error> {
+        let mut guard = InterfaceGuard::claim(transport)?;
+        let feature_index = feature_index(&mut *guard)?;
+
+        Ok(Session {
+            template: guard.profile().template.with_feature_index(feature_index),
+            guard,
+        })
+    }
+
+    pub fn send(&mut self, packet: Packet) -> Result<Reply, G213Error> {
+        self.guard.profile().check_packet(&packet)?;
+
+        send_to_keyboard(&mut *self.guard, &packet.encode(self.template))
+    }
+
+    pub fn send_all(&mut self, packets: &[Packet]) -> Result<(), G213Error> {
+        for packet in packets {
+            self.send(*packet)?;
+        }
+
+        Ok(())
+    }
 
-    let _bytes_sent = send_command(handle, &command).unwrap();
+    pub fn finish(self) -> Result<(), G213Error> {
+        self.guard.release()
+    }
 }
 
-fn send_breathe(handle: &DeviceHandle<GlobalContext>, speed: u16, colour: u32) {
-    let command = format!("11ff0c3a0002{:06x}{:04x}006400000000000000", colour, speed);
+// Sends a batch of packets in a single session
+pub fn send_packets<T: Transport>(transport: &mut T, packets: &[Packet]) -> Result<(), G213Error> {
+    // Check everything up front rather than leave the keyboard half done
+    for packet in packets {
+        transport.profile().check_packet(packet)?;
+    }
+
+    let mut session = Session::start(transport)?;
 
-    let _bytes_sent = send_command(handle, &command).unwrap();
+    let result = session.send_all(packets);
+
+    result.and(session.finish())
+}
+
+// The interface calls made while claiming, so that the clean up on failure
+// can be tested without a keyboard
+trait ClaimInterface {
+    fn kernel_driver_active(&self, interface: u8) -> rusb::Result<bool>;
+    fn detach_kernel_driver(&mut self, interface: u8) -> rusb::Result<()>;
+    fn attach_kernel_driver(&mut self, interface: u8) -> rusb::Result<()>;
+    fn claim_interface(&mut self, interface: u8) -> rusb::Result<()>;
 }
 
-fn send_cycle(handle: &DeviceHandle<GlobalContext>, speed: u16) {
-    let command = format!("11ff0c3a0003ffffff0000{:04x}64000000000000", speed);
+impl ClaimInterface for DeviceHandle<Context> {
+    fn kernel_driver_active(&self, interface: u8) -> rusb::Result<bool> {
+        DeviceHandle::kernel_driver_active(self, interface)
+    }
+
+    fn detach_kernel_driver(&mut self, interface: u8) -> rusb::Result<()> {
+        DeviceHandle::detach_kernel_driver(self, interface)
+    }
+
+    fn attach_kernel_driver(&mut self, interface: u8) -> rusb::Result<()> {
+        DeviceHandle::attach_kernel_driver(self, interface)
+    }
 
-    let _bytes_sent = send_command(handle, &command).unwrap();
+    fn claim_interface(&mut self, interface: u8) -> rusb::Result<()> {
+        DeviceHandle::claim_interface(self, interface)
+    }
 }
 
-fn do_show_info(descriptor: &DeviceDescriptor, handle: &DeviceHandle<GlobalContext>) {
-    let timeout = std::time::Duration::from_millis(TIMEOUT_MS);
-    let lang = handle.read_languages(timeout).unwrap()[0];
+// Detach the kernel driver, if it has the interface, and claim it. Returns
+// whether the driver was detached - if the claim fails it is put back.
+fn detach_and_claim<H: ClaimInterface>(handle: &mut H, interface: u8) -> Result<bool, G213Error> {
+    let detached = if handle.kernel_driver_active(interface)? {
+        handle.detach_kernel_driver(interface)?;
+        true
+    } else {
+        false
+    };
+
+    if let Err(err) = handle.claim_interface(interface) {
+        if detached {
+            handle
+                .attach_kernel_driver(interface)
+                .map_err(G213Error::DriverReattach)?;
+        }
 
-    println!(
-        "Manufacturer: {}",
-        handle
-            .read_manufacturer_string(lang, descriptor, timeout)
-            .unwrap()
-    );
+        return Err(err.into());
+    }
 
-    println!(
-        "Product:      {}",
-        handle
-            .read_product_string(lang, descriptor, timeout)
-            .unwrap()
-    );
+    Ok(detached)
+}
 
-    println!(
-        "Serial:       {}",
-        handle
-            .read_serial_number_string(lang, descriptor, timeout)
-            .unwrap()
-    );
+// The libusb backend
+pub struct UsbTransport {
+    device: Device<Context>,
+    handle: Option<DeviceHandle<Context>>,
+    kernel_driver_detached: bool,
+    info: Option<DeviceInfo>,
+    profile: &'static DeviceProfile,
 }
 
-pub fn find_g213_keyboard() -> Option<Device<GlobalContext>> {
-    devices().unwrap().iter().find(|device| {
-        let desc = device.device_descriptor().unwrap();
-        is_g213_keyboard(&desc)
+impl UsbTransport {
+    pub fn new(device: Device<Context>) -> Self {
+        let profile = device
+            .device_descriptor()
+            .ok()
+            .and_then(|desc| find_profile(desc.vendor_id(), desc.product_id()))
+            .unwrap_or(&G213_PROFILE);
+
+        UsbTransport {
+            device,
+            handle: None,
+            kernel_driver_detached: false,
+            info: None,
+            profile,
+        }
+    }
+
+    // Without opening the device
+    pub fn location(&self) -> String {
+        format!(
+            "USB bus {} address {}",
+            self.device.bus_number(),
+            self.device.address()
+        )
+    }
+
+    fn handle(&self) -> Result<&DeviceHandle<Context>, G213Error> {
+        self.handle.as_ref().ok_or(G213Error::NotFound)
+    }
+}
+
+impl Transport for UsbTransport {
+    fn open(&mut self) -> Result<(), G213Error> {
+        if self.handle.is_none() {
+            self.handle = Some(self.device.open()?);
+        }
+
+        Ok(())
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        let interface = self.profile.interface;
+        let handle = self.handle.as_mut().ok_or(G213Error::NotFound)?;
+
+        // Detach the kernel driver so that we can access the device
+        self.kernel_driver_detached = detach_and_claim(handle, interface)?;
+
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        let interface = self.profile.interface;
+        let handle = self.handle.as_mut().ok_or(G213Error::NotFound)?;
+
+        let released = handle.release_interface(interface);
+
+        // Let the kernel take over again, even if the release failed
+        if self.kernel_driver_detached {
+            handle
+                .attach_kernel_driver(interface)
+                .map_err(G213Error::DriverReattach)?;
+
+            self.kernel_driver_detached = false;
+        }
+
+        Ok(released?)
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        Ok(self.handle()?.write_control(
+            REQ_TYPE,
+            REQ,
+            VALUE,
+            self.profile.interface as u16,
+            bytes,
+            Duration::from_millis(TIMEOUT_MS),
+        )?)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        let handle = self.handle()?;
+
+        read_long_report(bytes, Duration::from_millis(TIMEOUT_MS), |report, left| {
+            Ok(handle.read_interrupt(ENDPOINT, report, left)?)
+        })
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        if self.info.is_none() {
+            self.info = Some(read_device_info(&self.device, self.handle()?)?);
+        }
+
+        Ok(self.info.clone().unwrap())
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        self.profile
+    }
+}
+
+fn port_path(device: &Device<Context>) -> Result<String, G213Error> {
+    let ports: Vec<_> = device
+        .port_numbers()?
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+
+    Ok(format!("{}-{}", device.bus_number(), ports.join(".")))
+}
+
+fn read_device_info(
+    device: &Device<Context>,
+    handle: &DeviceHandle<Context>,
+) -> Result<DeviceInfo, G213Error> {
+    let descriptor = device.device_descriptor()?;
+
+    let timeout = Duration::from_millis(TIMEOUT_MS);
+    let lang = *handle
+        .read_languages(timeout)?
+        .first()
+        .ok_or_else(|| G213Error::Protocol("no USB string languages".to_string()))?;
+
+    Ok(DeviceInfo {
+        bus: device.bus_number(),
+        address: device.address(),
+        port_path: port_path(device)?,
+        speed: format!("{:?}", device.speed()),
+        manufacturer: handle.read_manufacturer_string(lang, &descriptor, timeout)?,
+        product: handle.read_product_string(lang, &descriptor, timeout)?,
+        serial: handle.read_serial_number_string(lang, &descriptor, timeout)?,
     })
 }
 
-fn send_command_wrapper(
-    device: &Device<GlobalContext>,
-    cmd_fn: impl Fn(&DeviceHandle<GlobalContext>),
-) {
-    let mut handle = device.open().expect("Unable to open device!");
+pub fn find_g213_keyboards() -> Result<Vec<Device<Context>>, G213Error> {
+    let mut keyboards = vec![];
+
+    for device in usb_context()?.devices()?.iter() {
+        if is_supported_device(&device.device_descriptor()?) {
+            keyboards.push(device);
+        }
+    }
+
+    Ok(keyboards)
+}
+
+pub fn find_g213_keyboard() -> Result<Device<Context>, G213Error> {
+    find_g213_keyboards()?
+        .into_iter()
+        .next()
+        .ok_or(G213Error::NotFound)
+}
+
+// A keyboard's details, or why they couldn't be read
+pub type DeviceDetails = Result<DeviceInfo, G213Error>;
 
-    let mut kernel_driver_detached = false;
+// Every connected keyboard along with its details - one we can't open
+// doesn't stop the others being listed
+pub fn list_g213_keyboards() -> Result<Vec<(UsbTransport, DeviceDetails)>, G213Error> {
+    let mut keyboards = vec![];
 
-    // Then we detach the kernel driver so that we can access the device
-    if handle.kernel_driver_active(INDEX as u8).unwrap() {
-        handle
-            .detach_kernel_driver(INDEX as u8)
-            .expect("Unable to detach kernel USB driver");
+    for device in find_g213_keyboards()? {
+        let mut transport = UsbTransport::new(device);
+        let info = show_info(&mut transport);
 
-        kernel_driver_detached = true;
+        keyboards.push((transport, info));
     }
 
-    // Now we claim the interface
-    handle
-        .claim_interface(INDEX as u8)
-        .expect("Unable to claim interface for device");
+    Ok(keyboards)
+}
+
+// The keyboards picked by `selector`, all of them if there isn't one
+pub fn select_g213_keyboards(
+    selector: Option<&DeviceSelector>,
+) -> Result<Vec<UsbTransport>, G213Error> {
+    let mut unreadable = None;
+
+    let keyboards: Vec<_> = match selector {
+        Some(selector) => {
+            let listed = list_g213_keyboards()?;
+
+            // Perhaps the one wanted, so worth reporting if nothing matches
+            unreadable = listed
+                .iter()
+                .find_map(|(_, info)| info.as_ref().err().cloned());
+
+            listed
+                .into_iter()
+                .filter(|(_, info)| info.as_ref().is_ok_and(|info| selector.matches(info)))
+                .map(|(transport, _)| transport)
+                .collect()
+        }
+        // No need to open anything just to match everything
+        None => find_g213_keyboards()?
+            .into_iter()
+            .map(UsbTransport::new)
+            .collect(),
+    };
+
+    if keyboards.is_empty() {
+        Err(unreadable.unwrap_or(G213Error::NotFound))
+    } else {
+        Ok(keyboards)
+    }
+}
+
+// Where each keyboard is, and its details if they could be read
+pub type KeyboardList = Vec<(String, DeviceDetails)>;
+
+// Keyboards with a hidraw node we can open. These leave the kernel driver
+// alone and don't need root.
+fn hidraw_keyboards() -> Vec<HidrawTransport> {
+    find_hidraw_nodes(Path::new(SYSFS_ROOT), Path::new(DEV_ROOT))
+        .into_iter()
+        .map(HidrawTransport::new)
+        .filter_map(|mut transport| transport.open().ok().map(|_| transport))
+        .collect()
+}
+
+// Every connected keyboard using the best available backend - hidraw if we
+// can, otherwise libusb
+pub fn list_keyboards() -> Result<KeyboardList, G213Error> {
+    let hidraw = hidraw_keyboards();
+
+    if hidraw.is_empty() {
+        return Ok(list_g213_keyboards()?
+            .into_iter()
+            .map(|(transport, info)| (transport.location(), info))
+            .collect());
+    }
 
-    // Do our thing
-    cmd_fn(&handle);
+    Ok(hidraw
+        .into_iter()
+        .map(|mut transport| (transport.location(), transport.info()))
+        .collect())
+}
+
+// As `select_g213_keyboards`, preferring hidraw
+pub fn select_keyboards(
+    selector: Option<&DeviceSelector>,
+) -> Result<Vec<Box<dyn Transport>>, G213Error> {
+    let mut hidraw = hidraw_keyboards();
 
-    handle
-        .release_interface(INDEX as u8)
-        .expect("Unable to release interface for device");
+    if let Some(selector) = selector {
+        hidraw.retain_mut(|transport| transport.info().is_ok_and(|info| selector.matches(&info)));
+    }
 
-    // Let the kernel take over again
-    if kernel_driver_detached {
-        handle
-            .attach_kernel_driver(INDEX as u8)
-            .expect("Unable to attach kernel USB driver");
+    if hidraw.is_empty() {
+        Ok(select_g213_keyboards(selector)?
+            .into_iter()
+            .map(|transport| Box::new(transport) as Box<dyn Transport>)
+            .collect())
+    } else {
+        Ok(hidraw
+            .into_iter()
+            .map(|transport| Box::new(transport) as Box<dyn Transport>)
+            .collect())
     }
 }
 
-pub fn set_keyboard_colour(device: &Device<GlobalContext>, color: u32) {
-    send_command_wrapper(device, |h| {
-        send_keyboard_colour(h, KeyboardRegions::WholeKeyboard as u8, color);
-    });
+// Sends packets exactly as given, eg from a capture
+pub fn send_raw_packets<T: Transport>(
+    transport: &mut T,
+    packets: &[Vec<u8>],
+) -> Result<(), G213Error> {
+    let mut guard = InterfaceGuard::claim(transport)?;
+
+    let result = packets
+        .iter()
+        .try_for_each(|bytes| send_to_keyboard(&mut *guard, bytes).map(|_| ()));
+
+    result.and(guard.release())
 }
 
-pub fn set_region_colour(device: &Device<GlobalContext>, region: u8, color: u32) {
-    send_command_wrapper(device, |h| {
-        send_keyboard_colour(h, region, color);
-    });
+pub fn set_keyboard_colour<T: Transport>(transport: &mut T, color: u32) -> Result<(), G213Error> {
+    set_region_colour(transport, KeyboardRegions::WholeKeyboard as u8, color)
 }
 
-pub fn set_breathe(device: &Device<GlobalContext>, speed: u16, color: u32) {
-    send_command_wrapper(device, |h| {
-        send_breathe(h, speed, color);
-    });
+pub fn set_region_colour<T: Transport>(
+    transport: &mut T,
+    region: u8,
+    color: u32,
+) -> Result<(), G213Error> {
+    send_packets(
+        transport,
+        &[Packet::Colour {
+            region,
+            colour: color,
+        }],
+    )
 }
 
-pub fn set_cycle(device: &Device<GlobalContext>, speed: u16) {
-    send_command_wrapper(device, |h| {
-        send_cycle(h, speed);
-    });
+// Colours for regions 1, 2, ... in one go
+pub fn set_region_colours<T: Transport>(
+    transport: &mut T,
+    colours: &[u32],
+) -> Result<(), G213Error> {
+    let packets: Vec<_> = colours
+        .iter()
+        .enumerate()
+        .map(|(region, colour)| Packet::Colour {
+            region: (region + 1) as u8,
+            colour: *colour,
+        })
+        .collect();
+
+    send_packets(transport, &packets)
 }
 
-pub fn show_info(device: &Device<GlobalContext>) {
-    let descriptor = device.device_descriptor().unwrap();
+pub fn set_breathe<T: Transport>(
+    transport: &mut T,
+    speed: u16,
+    color: u32,
+) -> Result<(), G213Error> {
+    send_packets(
+        transport,
+        &[Packet::Breathe {
+            colour: color,
+            speed,
+            brightness: DEFAULT_BRIGHTNESS,
+        }],
+    )
+}
 
-    send_command_wrapper(device, |h| do_show_info(&descriptor, h));
+pub fn set_cycle<T: Transport>(transport: &mut T, speed: u16) -> Result<(), G213Error> {
+    send_packets(
+        transport,
+        &[Packet::Cycle {
+            speed,
+            brightness: DEFAULT_BRIGHTNESS,
+        }],
+    )
+}
+
+pub fn show_info<T: Transport>(transport: &mut T) -> Result<DeviceInfo, G213Error> {
+    transport.open()?;
+
+    transport.info()
 }
 
 #[cfg(test)]
@@ -219,6 +598,74 @@ mod g213_keyboard_tests {
     // use rusb::{ffi::libusb_device_descriptor, DeviceDescriptor};
 
     use super::*;
+    use crate::transport::MockTransport;
+
+    // A keyboard interface, with the kernel driver attached to start with
+    #[derive(Default)]
+    struct MockInterface {
+        detached: bool,
+        claimed: bool,
+        fail_claim: bool,
+    }
+
+    impl ClaimInterface for MockInterface {
+        fn kernel_driver_active(&self, _interface: u8) -> rusb::Result<bool> {
+            Ok(!self.detached)
+        }
+
+        fn detach_kernel_driver(&mut self, _interface: u8) -> rusb::Result<()> {
+            self.detached = true;
+            Ok(())
+        }
+
+        fn attach_kernel_driver(&mut self, _interface: u8) -> rusb::Result<()> {
+            self.detached = false;
+            Ok(())
+        }
+
+        fn claim_interface(&mut self, _interface: u8) -> rusb::Result<()> {
+            if self.fail_claim {
+                return Err(rusb::Error::Busy);
+            }
+
+            self.claimed = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn claim_detaches_kernel_driver() {
+        let mut handle = MockInterface::default();
+
+        assert!(detach_and_claim(&mut handle, 1).unwrap());
+        assert!(handle.detached && handle.claimed);
+    }
+
+    #[test]
+    fn failed_claim_reattaches_kernel_driver() {
+        let mut handle = MockInterface {
+            fail_claim: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            detach_and_claim(&mut handle, 1),
+            Err(G213Error::Busy)
+        ));
+        assert!(!handle.detached);
+    }
+
+    #[test]
+    fn failed_claim_leaves_detached_driver_alone() {
+        let mut handle = MockInterface {
+            detached: true,
+            fail_claim: true,
+            ..Default::default()
+        };
+
+        assert!(detach_and_claim(&mut handle, 1).is_err());
+        assert!(handle.detached);
+    }
 
     // NOTE: A lot of work to test a one line function...
 
@@ -260,20 +707,177 @@ mod g213_keyboard_tests {
     fn a_g213_keyboard() {
         let descriptor = GoodG213DeviceDescriptor {};
 
-        assert_eq!(is_g213_keyboard(&descriptor), true);
+        assert!(is_g213_keyboard(&descriptor));
     }
 
     #[test]
     fn not_a_logitech_device() {
         let descriptor = NonLogitechDeviceDescriptor {};
 
-        assert_eq!(is_g213_keyboard(&descriptor), false);
+        assert!(!is_g213_keyboard(&descriptor));
     }
 
     #[test]
     fn not_a_g213_keyboard() {
         let descriptor = NonG213DeviceDescriptor {};
 
-        assert_eq!(is_g213_keyboard(&descriptor), false);
+        assert!(!is_g213_keyboard(&descriptor));
+    }
+
+    #[test]
+    fn rejected_packet_is_an_error() {
+        let mut mock = MockTransport::new();
+        mock.replies
+            .push_back(hex::decode("11ffff0c3a090000000000000000000000000000").unwrap());
+
+        assert_eq!(
+            set_cycle(&mut mock, 1000),
+            Err(G213Error::Rejected {
+                feature_index: 0x0c,
+                function: 0x3a,
+                code: 0x09
+            })
+        );
+        assert!(!mock.claimed);
+    }
+
+    #[test]
+    fn session_claims_once_for_many_packets() {
+        let mut mock = MockTransport::new();
+
+        set_region_colours(&mut mock, &[0xff0000, 0x00ff00, 0x0000ff]).unwrap();
+
+        assert_eq!(mock.effect_packets().len(), 3);
+        assert_eq!(mock.claims, 1);
+        assert_eq!(mock.releases, 1);
+    }
+
+    #[test]
+    fn session_stops_at_first_failure_and_releases() {
+        let mut mock = MockTransport::new();
+        mock.replies.push_back(
+            Packet::Colour {
+                region: 1,
+                colour: 0xff0000,
+            }
+            .encode(crate::packet::G213_TEMPLATE)
+            .to_vec(),
+        );
+        mock.replies
+            .push_back(hex::decode("11ffff0c3a020000000000000000000000000000").unwrap());
+
+        assert!(set_region_colours(&mut mock, &[0xff0000, 0x00ff00, 0x0000ff]).is_err());
+        assert_eq!(mock.effect_packets().len(), 2);
+        assert!(!mock.claimed);
+    }
+
+    #[test]
+    fn discovered_feature_index_is_used() {
+        let mut mock = MockTransport::new();
+        mock.info.serial = "FEATURE0E".to_string();
+        mock.feature_index = 0x0e;
+
+        set_cycle(&mut mock, 1000).unwrap();
+
+        assert_eq!(mock.written.len(), 2);
+        assert_eq!(
+            mock.written[0],
+            feature_request(COLOUR_LED_EFFECTS).to_vec()
+        );
+        assert_eq!(mock.written[1][2], 0x0e);
+    }
+
+    #[test]
+    fn feature_index_is_cached_per_serial() {
+        let mut mock = MockTransport::new();
+        mock.info.serial = "FEATURECACHED".to_string();
+
+        set_cycle(&mut mock, 1000).unwrap();
+        set_cycle(&mut mock, 2000).unwrap();
+
+        let queries = mock
+            .written
+            .iter()
+            .filter(|bytes| crate::packet::is_feature_request(bytes))
+            .count();
+
+        assert_eq!(queries, 1);
+        assert_eq!(mock.written.len(), 3);
+    }
+
+    fn feature_queries(mock: &MockTransport) -> usize {
+        mock.written
+            .iter()
+            .filter(|bytes| crate::packet::is_feature_request(bytes))
+            .count()
+    }
+
+    #[test]
+    fn no_serial_is_not_cached() {
+        let mut mock = MockTransport::new();
+        mock.info.serial = String::new();
+
+        set_cycle(&mut mock, 1000).unwrap();
+        set_cycle(&mut mock, 2000).unwrap();
+
+        assert_eq!(feature_queries(&mock), 2);
+    }
+
+    #[test]
+    fn unreadable_info_still_sends() {
+        let mut mock = MockTransport::new();
+        mock.fail_info = Some(G213Error::Timeout);
+
+        set_cycle(&mut mock, 1000).unwrap();
+        set_cycle(&mut mock, 2000).unwrap();
+
+        assert_eq!(feature_queries(&mock), 2);
+        assert_eq!(mock.effect_packets().len(), 2);
+    }
+
+    #[test]
+    fn missing_feature_is_an_error() {
+        let mut mock = MockTransport::new();
+        mock.info.serial = "NOFEATURE".to_string();
+        mock.feature_index = 0;
+
+        assert!(matches!(
+            set_cycle(&mut mock, 1000),
+            Err(G213Error::Protocol(_))
+        ));
+        assert!(!mock.claimed);
+    }
+
+    #[test]
+    fn keyboard_regions_from_u8() {
+        assert_eq!(
+            KeyboardRegions::try_from(0),
+            Ok(KeyboardRegions::WholeKeyboard)
+        );
+        assert_eq!(KeyboardRegions::try_from(5), Ok(KeyboardRegions::Region5));
+        assert!(KeyboardRegions::try_from(9).is_err());
+    }
+
+    #[test]
+    fn supported_devices() {
+        assert!(is_supported_device(&GoodG213DeviceDescriptor {}));
+        assert!(!is_supported_device(&NonLogitechDeviceDescriptor {}));
+        assert!(!is_supported_device(&NonG213DeviceDescriptor {}));
+    }
+
+    #[test]
+    fn unsupported_packets_are_not_sent() {
+        let mut mock = MockTransport::new();
+        mock.profile = &crate::device_profiles::G413_PROFILE;
+
+        assert!(matches!(
+            set_cycle(&mut mock, 1000),
+            Err(G213Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            set_region_colours(&mut mock, &[0xff0000, 0x00ff00]),
+            Err(G213Error::Unsupported(_))
+        ));
+        assert!(mock.written.is_empty());
     }
 }
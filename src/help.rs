@@ -0,0 +1,207 @@
+use crate::options::OPTION_HELP;
+use crate::registry::{CommandSpec, COMMANDS};
+
+// A two column table, as markdown for the README or boxed in for the terminal
+fn table(headings: [&str; 2], rows: &[(String, String)], boxed: bool) -> String {
+    let width = |column: usize, heading: &str| {
+        rows.iter()
+            .map(|row| if column == 0 { &row.0 } else { &row.1 })
+            .map(|cell| cell.chars().count())
+            .chain([heading.chars().count()])
+            .max()
+            .unwrap_or(0)
+    };
+    let widths = [width(0, headings[0]), width(1, headings[1])];
+
+    let line = |left: &str, middle: &str, right: &str| {
+        format!(
+            "{} {} {} {} {}\n",
+            left,
+            "-".repeat(widths[0]),
+            middle,
+            "-".repeat(widths[1]),
+            right
+        )
+    };
+    let row = |first: &str, second: &str| {
+        format!(
+            "| {:w0$} | {:w1$} |\n",
+            first,
+            second,
+            w0 = widths[0],
+            w1 = widths[1]
+        )
+    };
+
+    let mut text = String::new();
+
+    if boxed {
+        text += &line("+", "+", "+");
+    }
+
+    text += &row(headings[0], headings[1]);
+    text += &line("|", if boxed { "+" } else { "|" }, "|");
+
+    for (first, second) in rows {
+        text += &row(first, second);
+    }
+
+    if boxed {
+        text += &line("+", "+", "+");
+    }
+
+    text
+}
+
+fn example_rows(spec: &CommandSpec) -> Vec<(String, String)> {
+    spec.examples
+        .iter()
+        .flat_map(|example| {
+            example.details.iter().enumerate().map(|(index, details)| {
+                let usage = if index == 0 {
+                    format!("`{}`", example.usage)
+                } else {
+                    String::new()
+                };
+
+                (usage, details.to_string())
+            })
+        })
+        .collect()
+}
+
+pub fn commands_table(boxed: bool) -> String {
+    let rows: Vec<_> = COMMANDS
+        .iter()
+        .flat_map(|spec| {
+            [(spec.help.to_string(), String::new())]
+                .into_iter()
+                .chain(example_rows(spec))
+        })
+        .collect();
+
+    table(["Command", ""], &rows, boxed)
+}
+
+pub fn abbreviations_table(boxed: bool) -> String {
+    let rows: Vec<_> = COMMANDS
+        .iter()
+        .map(|spec| (spec.name.to_string(), spec.aliases.join(" or ")))
+        .collect();
+
+    table(["Command", "Abbreviation"], &rows, boxed)
+}
+
+pub fn options_table(boxed: bool) -> String {
+    let rows: Vec<_> = OPTION_HELP
+        .iter()
+        .map(|(option, details)| (format!("`{}`", option), details.to_string()))
+        .collect();
+
+    table(["Option", "Details"], &rows, boxed)
+}
+
+// Everything about one command
+pub fn command_help(spec: &CommandSpec) -> String {
+    let mut text = format!(
+        "{} - {}\n\nUsage: g213-cols {}\n",
+        spec.name,
+        spec.help,
+        spec.usage()
+    );
+
+    if !spec.aliases.is_empty() {
+        text += &format!("Abbreviation: {}\n", spec.aliases.join(" or "));
+    }
+
+    if !spec.args.is_empty() {
+        let width = spec
+            .args
+            .iter()
+            .map(|arg| arg.name.len())
+            .max()
+            .unwrap_or(0);
+
+        text += "\nArguments:\n";
+
+        for arg in spec.args {
+            let mut details = arg.help.to_string();
+
+            if arg.kind.has_range() {
+                details += &format!(" - {}", arg.kind.expected());
+            }
+            if arg.optional {
+                details += ", optional";
+            }
+
+            text += &format!("  {:width$}  {}\n", arg.name, details, width = width);
+        }
+    }
+
+    text += "\nExamples:\n";
+
+    for example in spec.examples {
+        text += &format!("  {}\n", example.usage);
+
+        for details in example.details {
+            text += &format!("      {}\n", details);
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod help_tests {
+    use super::*;
+    use crate::registry::find_command;
+
+    const README: &str = include_str!("../README.md");
+
+    #[test]
+    fn readme_commands_table_is_current() {
+        assert!(
+            README.contains(&commands_table(false)),
+            "README.md commands table differs from:\n{}",
+            commands_table(false)
+        );
+    }
+
+    #[test]
+    fn readme_abbreviations_table_is_current() {
+        assert!(
+            README.contains(&abbreviations_table(false)),
+            "README.md abbreviations table differs from:\n{}",
+            abbreviations_table(false)
+        );
+    }
+
+    #[test]
+    fn readme_options_table_is_current() {
+        assert!(
+            README.contains(&options_table(false)),
+            "README.md options table differs from:\n{}",
+            options_table(false)
+        );
+    }
+
+    #[test]
+    fn boxed_table() {
+        let rows = vec![("a".to_string(), "bb".to_string())];
+
+        assert_eq!(
+            table(["A", "B"], &rows, true),
+            "+ - + -- +\n| A | B  |\n| - + -- |\n| a | bb |\n+ - + -- +\n"
+        );
+    }
+
+    #[test]
+    fn command_help_shows_ranges_and_examples() {
+        let help = command_help(find_command("region").unwrap());
+
+        assert!(help.contains("Usage: g213-cols region region [colour]"));
+        assert!(help.contains("Abbreviation: r\n"));
+        assert!(help.contains("a number from 1 to 5"));
+        assert!(help.contains("  g213-cols region 2 [colour]\n"));
+    }
+}
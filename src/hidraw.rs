@@ -0,0 +1,284 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::device_profiles::{find_profile, DeviceProfile};
+use crate::error::G213Error;
+use crate::packet::read_long_report;
+use crate::transport::{DeviceInfo, Transport};
+
+pub const SYSFS_ROOT: &str = "/sys";
+pub const DEV_ROOT: &str = "/dev";
+
+const TIMEOUT_MS: u64 = 50;
+
+// A keyboard's hidraw node and what sysfs tells us about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct HidrawNode {
+    pub path: PathBuf,
+    pub profile: &'static DeviceProfile,
+    pub info: DeviceInfo,
+}
+
+fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+// HID_ID=0003:0000046D:0000C336 - bus type, vendor and product
+fn parse_hid_id(uevent: &str) -> Option<(u16, u16)> {
+    let id = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("HID_ID="))?;
+
+    let parts: Vec<_> = id.split(':').collect();
+
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let vendor = u32::from_str_radix(parts[1], 16).ok()?;
+    let product = u32::from_str_radix(parts[2], 16).ok()?;
+
+    Some((vendor as u16, product as u16))
+}
+
+// hidrawN/device is the HID device, its parent the USB interface and the
+// interface's parent the USB device
+fn read_node(sysfs_root: &Path, dev_root: &Path, name: &str) -> Option<HidrawNode> {
+    let hid_dir =
+        fs::canonicalize(sysfs_root.join("class/hidraw").join(name).join("device")).ok()?;
+
+    let (vendor, product) = parse_hid_id(&fs::read_to_string(hid_dir.join("uevent")).ok()?)?;
+    let profile = find_profile(vendor, product)?;
+
+    let interface_dir = hid_dir.parent()?;
+    let interface = u8::from_str_radix(&read_attr(interface_dir, "bInterfaceNumber")?, 16).ok()?;
+
+    if interface != profile.interface {
+        return None;
+    }
+
+    let usb_dir = interface_dir.parent()?;
+
+    Some(HidrawNode {
+        path: dev_root.join(name),
+        profile,
+        info: DeviceInfo {
+            bus: read_attr(usb_dir, "busnum")?.parse().ok()?,
+            address: read_attr(usb_dir, "devnum")?.parse().ok()?,
+            port_path: usb_dir.file_name()?.to_string_lossy().to_string(),
+            speed: format!("{} Mb/s", read_attr(usb_dir, "speed").unwrap_or_default()),
+            manufacturer: read_attr(usb_dir, "manufacturer").unwrap_or_default(),
+            product: read_attr(usb_dir, "product").unwrap_or_default(),
+            serial: read_attr(usb_dir, "serial").unwrap_or_default(),
+        },
+    })
+}
+
+// Every supported keyboard's hidraw node, in name order
+pub fn find_hidraw_nodes(sysfs_root: &Path, dev_root: &Path) -> Vec<HidrawNode> {
+    let Ok(entries) = fs::read_dir(sysfs_root.join("class/hidraw")) else {
+        return vec![];
+    };
+
+    let mut names: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+
+    names
+        .iter()
+        .filter_map(|name| read_node(sysfs_root, dev_root, name))
+        .collect()
+}
+
+// The hidraw backend - the kernel driver stays attached, so there is
+// nothing to detach, claim or release
+pub struct HidrawTransport {
+    node: HidrawNode,
+    file: Option<File>,
+}
+
+impl HidrawTransport {
+    pub fn new(node: HidrawNode) -> Self {
+        HidrawTransport { node, file: None }
+    }
+
+    pub fn location(&self) -> String {
+        self.node.path.display().to_string()
+    }
+
+    fn file(&mut self) -> Result<&mut File, G213Error> {
+        self.file.as_mut().ok_or(G213Error::NotFound)
+    }
+}
+
+fn wait_readable(file: &File, timeout: Duration) -> Result<(), G213Error> {
+    let mut poll_fd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as i32) } {
+        0 => Err(G213Error::Timeout),
+        n if n < 0 => Err(std::io::Error::last_os_error().into()),
+        _ => Ok(()),
+    }
+}
+
+impl Transport for HidrawTransport {
+    fn open(&mut self) -> Result<(), G213Error> {
+        if self.file.is_none() {
+            self.file = Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&self.node.path)?,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        self.file().map(|_| ())
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        Ok(())
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        Ok(self.file()?.write(bytes)?)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        let file = self.file()?;
+
+        // Other reports can turn up on the same node, all within one timeout
+        read_long_report(bytes, Duration::from_millis(TIMEOUT_MS), |report, left| {
+            wait_readable(file, left)?;
+
+            Ok(file.read(report)?)
+        })
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        Ok(self.node.info.clone())
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        self.node.profile
+    }
+}
+
+#[cfg(test)]
+mod hidraw_tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::device_profiles::G213_PROFILE;
+
+    struct FakeSysfs {
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("g213-sysfs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("class/hidraw")).unwrap();
+
+            FakeSysfs { root }
+        }
+
+        fn add(&self, hidraw: &str, port: &str, interface: u8, hid_id: &str) {
+            let usb_dir = self.root.join("devices/usb1").join(port);
+            let interface_dir = usb_dir.join(format!("{}:1.{}", port, interface));
+            let hid_dir = interface_dir.join(format!("{}.0001", hid_id.replace("0000", "")));
+
+            fs::create_dir_all(&hid_dir).unwrap();
+
+            for (name, value) in [
+                ("busnum", "1"),
+                ("devnum", "7"),
+                ("speed", "12"),
+                ("manufacturer", "Logitech"),
+                ("product", "G213 Prodigy Gaming Keyboard"),
+                ("serial", "0123456789AB"),
+            ] {
+                fs::write(usb_dir.join(name), format!("{}\n", value)).unwrap();
+            }
+
+            fs::write(
+                interface_dir.join("bInterfaceNumber"),
+                format!("{:02x}\n", interface),
+            )
+            .unwrap();
+            fs::write(
+                hid_dir.join("uevent"),
+                format!("DRIVER=hid-generic\nHID_ID={}\nHID_NAME=Logitech\n", hid_id),
+            )
+            .unwrap();
+
+            let class_dir = self.root.join("class/hidraw").join(hidraw);
+            fs::create_dir_all(&class_dir).unwrap();
+            symlink(&hid_dir, class_dir.join("device")).unwrap();
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn parse_uevent_hid_id() {
+        assert_eq!(
+            parse_hid_id("DRIVER=hid-generic\nHID_ID=0003:0000046D:0000C336\n"),
+            Some((0x046d, 0xc336))
+        );
+        assert_eq!(parse_hid_id("DRIVER=hid-generic\n"), None);
+    }
+
+    #[test]
+    fn finds_g213_interface_node() {
+        let sysfs = FakeSysfs::new("g213");
+        sysfs.add("hidraw2", "1-2", 0, "0003:0000046D:0000C336");
+        sysfs.add("hidraw3", "1-2", 1, "0003:0000046D:0000C336");
+
+        let nodes = find_hidraw_nodes(&sysfs.root, Path::new("/dev"));
+
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].path, PathBuf::from("/dev/hidraw3"));
+        assert_eq!(nodes[0].profile, &G213_PROFILE);
+        assert_eq!(nodes[0].info.bus, 1);
+        assert_eq!(nodes[0].info.address, 7);
+        assert_eq!(nodes[0].info.port_path, "1-2");
+        assert_eq!(nodes[0].info.serial, "0123456789AB");
+    }
+
+    #[test]
+    fn ignores_other_devices() {
+        let sysfs = FakeSysfs::new("other");
+        sysfs.add("hidraw0", "1-3", 1, "0003:0000046D:0000C52B");
+        sysfs.add("hidraw1", "1-4", 1, "0003:00001234:0000C336");
+
+        assert!(find_hidraw_nodes(&sysfs.root, Path::new("/dev")).is_empty());
+    }
+
+    #[test]
+    fn no_hidraw_class() {
+        let root = std::env::temp_dir().join("g213-sysfs-does-not-exist");
+
+        assert!(find_hidraw_nodes(&root, Path::new("/dev")).is_empty());
+    }
+}
@@ -1,6 +1,23 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod capture;
 pub mod commands;
+pub mod completions;
+pub mod config;
+pub mod device_profiles;
+pub mod error;
 pub mod g213_keyboard;
+pub mod help;
+pub mod hidraw;
+pub mod options;
+pub mod packet;
+pub mod privileges;
+pub mod profiles;
+pub mod registry;
+pub mod state;
+pub mod suggest;
+pub mod transport;
+pub mod udev;
+pub mod watch;
 pub mod x11_colours;
@@ -0,0 +1,196 @@
+use std::path::PathBuf;
+
+use crate::transport::DeviceSelector;
+
+// Global options, which can appear anywhere on the command line
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub device: Option<DeviceSelector>,
+    pub all: bool,
+    pub dry_run: bool,
+    pub capture: Option<PathBuf>,
+    pub reroll: bool,
+    pub config: Option<PathBuf>,
+}
+
+// Shown in the help and README
+pub const OPTION_HELP: [(&str, &str); 8] = [
+    (
+        "--device bus:addr",
+        "use the keyboard at USB bus and address, eg 1:5",
+    ),
+    (
+        "--device port",
+        "use the keyboard at USB port path, eg 1-2.3",
+    ),
+    (
+        "--device serial",
+        "use the keyboard with this serial number",
+    ),
+    (
+        "--all",
+        "run the command on every connected (or selected) keyboard",
+    ),
+    (
+        "--dry-run",
+        "print the packets that would be sent, without a keyboard",
+    ),
+    (
+        "--capture file",
+        "record every packet sent and received, with timestamps",
+    ),
+    (
+        "--reroll",
+        "pick new random colours when using the saved command",
+    ),
+    (
+        "--config file",
+        "read and save the saved command in this file instead",
+    ),
+];
+
+fn option_value(
+    name: &str,
+    inline: Option<&str>,
+    rest: &mut impl Iterator<Item = String>,
+) -> Result<String, String> {
+    match inline {
+        Some(value) => Ok(value.to_string()),
+        None => rest
+            .next()
+            .ok_or_else(|| format!("'--{}' needs a value", name)),
+    }
+}
+
+// Splits the options from the command and its arguments
+pub fn parse_options(args: &[String]) -> Result<(Options, Vec<String>), String> {
+    let mut options = Options::default();
+    let mut remaining = vec![];
+
+    let mut iter = args.iter().cloned();
+
+    while let Some(arg) = iter.next() {
+        let Some(option) = arg.strip_prefix("--") else {
+            remaining.push(arg);
+            continue;
+        };
+
+        let (name, inline) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+
+        match name {
+            "device" => {
+                let value = option_value(name, inline, &mut iter)?;
+                options.device = Some(value.parse()?);
+            }
+            "all" => options.all = true,
+            "dry-run" => options.dry_run = true,
+            "reroll" => options.reroll = true,
+            "config" => {
+                options.config = Some(option_value(name, inline, &mut iter)?.into());
+            }
+            "capture" => {
+                options.capture = Some(option_value(name, inline, &mut iter)?.into());
+            }
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+
+    Ok((options, remaining))
+}
+
+#[cfg(test)]
+mod options_tests {
+    use super::*;
+
+    fn to_string_vec(words: Vec<&str>) -> Vec<String> {
+        words.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn no_options() {
+        let args = to_string_vec(vec!["colour", "red"]);
+
+        assert_eq!(parse_options(&args), Ok((Options::default(), args)));
+    }
+
+    #[test]
+    fn device_option_anywhere() {
+        let args = to_string_vec(vec!["colour", "--device", "1:5", "red"]);
+
+        let (options, rest) = parse_options(&args).unwrap();
+
+        assert_eq!(options.device, Some(DeviceSelector::BusAddress(1, 5)));
+        assert_eq!(rest, to_string_vec(vec!["colour", "red"]));
+    }
+
+    #[test]
+    fn device_option_inline_value() {
+        let args = to_string_vec(vec!["--device=ABC123", "--all", "cycle", "1000"]);
+
+        let (options, rest) = parse_options(&args).unwrap();
+
+        assert_eq!(
+            options.device,
+            Some(DeviceSelector::Serial("ABC123".to_string()))
+        );
+        assert!(options.all);
+        assert_eq!(rest, to_string_vec(vec!["cycle", "1000"]));
+    }
+
+    #[test]
+    fn dry_run_option() {
+        let args = to_string_vec(vec!["regions", "red", "--dry-run"]);
+
+        let (options, rest) = parse_options(&args).unwrap();
+
+        assert!(options.dry_run);
+        assert_eq!(rest, to_string_vec(vec!["regions", "red"]));
+    }
+
+    #[test]
+    fn reroll_option() {
+        let args = to_string_vec(vec!["--reroll"]);
+
+        let (options, rest) = parse_options(&args).unwrap();
+
+        assert!(options.reroll);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn capture_option() {
+        let args = to_string_vec(vec!["--capture", "/tmp/g213.cap", "colour", "red"]);
+
+        let (options, rest) = parse_options(&args).unwrap();
+
+        assert_eq!(options.capture, Some(PathBuf::from("/tmp/g213.cap")));
+        assert_eq!(rest, to_string_vec(vec!["colour", "red"]));
+    }
+
+    #[test]
+    fn config_option() {
+        let args = to_string_vec(vec!["saved", "--config=/tmp/g213.json"]);
+
+        let (options, rest) = parse_options(&args).unwrap();
+
+        assert_eq!(options.config, Some(PathBuf::from("/tmp/g213.json")));
+        assert_eq!(rest, to_string_vec(vec!["saved"]));
+    }
+
+    #[test]
+    fn missing_option_value() {
+        let args = to_string_vec(vec!["colour", "--device"]);
+
+        assert!(parse_options(&args).is_err());
+    }
+
+    #[test]
+    fn unknown_option() {
+        let args = to_string_vec(vec!["--colour", "red"]);
+
+        assert!(parse_options(&args).is_err());
+    }
+}
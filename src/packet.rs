@@ -0,0 +1,524 @@
+// The G213 HID++ lighting packets
+//
+// Every packet is a 20 byte HID++ 2.0 long report. The feature index is
+// where the device keeps its colour LED effects feature - see `feature_request`.
+// The feature index and function byte can differ between models, so they
+// come from a `PacketTemplate`.
+//
+// | 0    | 1      | 2       | 3        | 4      | 5      | 6..=19       |
+// | 0x11 | device | feature | function | region | effect | effect data  |
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::G213Error;
+
+pub const CMD_LEN: usize = 20;
+pub const DEFAULT_BRIGHTNESS: u8 = 100;
+
+const LONG_REPORT: u8 = 0x11;
+const DEVICE_INDEX: u8 = 0xff;
+// Where the G213 keeps its colour LED effects feature
+pub const DEFAULT_FEATURE_INDEX: u8 = 0x0c;
+pub const COLOUR_LED_EFFECTS: u16 = 0x8070;
+
+// Function in the high nibble, our software id in the low one
+const SET_EFFECT: u8 = 0x3a;
+const FUNCTION_MASK: u8 = 0xf0;
+const ROOT_FEATURE_INDEX: u8 = 0x00;
+const GET_FEATURE: u8 = 0x0a;
+
+const EFFECT_FIXED: u8 = 0x01;
+const EFFECT_BREATHE: u8 = 0x02;
+const EFFECT_CYCLE: u8 = 0x03;
+
+// Replies with this in the feature index position are HID++ error frames
+const ERROR_FRAME: u8 = 0xff;
+
+const FIXED_PERSIST: u8 = 0x02;
+const CYCLE_COLOUR: u32 = 0xffffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketTemplate {
+    pub feature_index: u8,
+    pub set_effect: u8,
+}
+
+pub const G213_TEMPLATE: PacketTemplate = PacketTemplate {
+    feature_index: DEFAULT_FEATURE_INDEX,
+    set_effect: SET_EFFECT,
+};
+
+impl PacketTemplate {
+    pub fn with_feature_index(self, feature_index: u8) -> Self {
+        PacketTemplate {
+            feature_index,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Fixed,
+    Breathe,
+    Cycle,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet {
+    Colour {
+        region: u8,
+        colour: u32,
+    },
+    Breathe {
+        colour: u32,
+        speed: u16,
+        brightness: u8,
+    },
+    Cycle {
+        speed: u16,
+        brightness: u8,
+    },
+}
+
+fn put_colour(bytes: &mut [u8], colour: u32) {
+    bytes[0..3].copy_from_slice(&colour.to_be_bytes()[1..]);
+}
+
+fn get_colour(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]])
+}
+
+fn get_speed(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+impl Packet {
+    pub fn effect(&self) -> Effect {
+        match self {
+            Packet::Colour { .. } => Effect::Fixed,
+            Packet::Breathe { .. } => Effect::Breathe,
+            Packet::Cycle { .. } => Effect::Cycle,
+        }
+    }
+
+    pub fn encode(&self, template: PacketTemplate) -> [u8; CMD_LEN] {
+        let mut bytes = [0u8; CMD_LEN];
+
+        bytes[0] = LONG_REPORT;
+        bytes[1] = DEVICE_INDEX;
+        bytes[2] = template.feature_index;
+        bytes[3] = template.set_effect;
+
+        match *self {
+            Packet::Colour { region, colour } => {
+                bytes[4] = region;
+                bytes[5] = EFFECT_FIXED;
+                put_colour(&mut bytes[6..], colour);
+                bytes[9] = FIXED_PERSIST;
+            }
+            Packet::Breathe {
+                colour,
+                speed,
+                brightness,
+            } => {
+                bytes[5] = EFFECT_BREATHE;
+                put_colour(&mut bytes[6..], colour);
+                bytes[9..11].copy_from_slice(&speed.to_be_bytes());
+                bytes[12] = brightness;
+            }
+            Packet::Cycle { speed, brightness } => {
+                bytes[5] = EFFECT_CYCLE;
+                put_colour(&mut bytes[6..], CYCLE_COLOUR);
+                bytes[11..13].copy_from_slice(&speed.to_be_bytes());
+                bytes[13] = brightness;
+            }
+        }
+
+        bytes
+    }
+
+    // Only accepts exactly what `encode` produces, for any template
+    pub fn decode(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() != CMD_LEN
+            || bytes[0..2] != [LONG_REPORT, DEVICE_INDEX]
+            || bytes[3] & FUNCTION_MASK != SET_EFFECT & FUNCTION_MASK
+        {
+            return None;
+        }
+
+        let packet = match bytes[5] {
+            EFFECT_FIXED => Packet::Colour {
+                region: bytes[4],
+                colour: get_colour(&bytes[6..]),
+            },
+            EFFECT_BREATHE => Packet::Breathe {
+                colour: get_colour(&bytes[6..]),
+                speed: get_speed(&bytes[9..]),
+                brightness: bytes[12],
+            },
+            EFFECT_CYCLE => Packet::Cycle {
+                speed: get_speed(&bytes[11..]),
+                brightness: bytes[13],
+            },
+            _ => return None,
+        };
+
+        // Anything we don't encode must be zero, so re-encoding must match exactly
+        let template = PacketTemplate {
+            feature_index: bytes[2],
+            set_effect: bytes[3],
+        };
+
+        if packet.encode(template) == bytes {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+}
+
+// Asks the root feature where `feature_id` lives
+pub fn feature_request(feature_id: u16) -> [u8; CMD_LEN] {
+    let mut bytes = [0u8; CMD_LEN];
+
+    bytes[0] = LONG_REPORT;
+    bytes[1] = DEVICE_INDEX;
+    bytes[2] = ROOT_FEATURE_INDEX;
+    bytes[3] = GET_FEATURE;
+    bytes[4..6].copy_from_slice(&feature_id.to_be_bytes());
+
+    bytes
+}
+
+pub fn is_feature_request(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && bytes[2] == ROOT_FEATURE_INDEX && bytes[3] == GET_FEATURE
+}
+
+// The keyboard's answer to a packet, read from the interrupt endpoint
+//
+// | 0    | 1      | 2       | 3        | 4..      |
+// | 0x11 | device | feature | function | params   |
+//
+// Error frames instead look like
+//
+// | 0    | 1      | 2    | 3       | 4        | 5    |
+// | 0x11 | device | 0xff | feature | function | code |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub feature_index: u8,
+    pub function: u8,
+    pub params: Vec<u8>,
+}
+
+impl Reply {
+    pub fn parse(bytes: &[u8]) -> Result<Reply, G213Error> {
+        if bytes.len() < 6 || bytes[0] != LONG_REPORT {
+            return Err(G213Error::Protocol(format!(
+                "unexpected reply: {}",
+                hex::encode(bytes)
+            )));
+        }
+
+        if bytes[2] == ERROR_FRAME {
+            return Err(G213Error::Rejected {
+                feature_index: bytes[3],
+                function: bytes[4],
+                code: bytes[5],
+            });
+        }
+
+        Ok(Reply {
+            feature_index: bytes[2],
+            function: bytes[3],
+            params: bytes[4..].to_vec(),
+        })
+    }
+
+    // From the answer to a `feature_request` - index 0 means not supported
+    pub fn feature_index(&self) -> Option<u8> {
+        match self.params.first() {
+            Some(0) | None => None,
+            Some(index) => Some(*index),
+        }
+    }
+
+    // The keyboard echoes the feature index and function it is answering
+    pub fn check_echo(&self, sent: &[u8]) -> Result<(), G213Error> {
+        if self.feature_index == sent[2] && self.function == sent[3] {
+            Ok(())
+        } else {
+            Err(G213Error::Protocol(format!(
+                "reply for feature {:#04x} function {:#04x}, expected {:#04x} {:#04x}",
+                self.feature_index, self.function, sent[2], sent[3]
+            )))
+        }
+    }
+}
+
+// Key presses and other reports can turn up alongside the replies. Reads
+// with `read`, which is given the time left, until a HID++ long report
+// arrives or `timeout` is up.
+pub fn read_long_report(
+    bytes: &mut [u8],
+    timeout: Duration,
+    mut read: impl FnMut(&mut [u8], Duration) -> Result<usize, G213Error>,
+) -> Result<usize, G213Error> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        // Anything under a millisecond would round down to no timeout at all
+        let left = deadline.saturating_duration_since(Instant::now());
+
+        if left < Duration::from_millis(1) {
+            return Err(G213Error::Timeout);
+        }
+
+        let mut report = [0u8; CMD_LEN];
+        let len = read(&mut report, left)?;
+
+        if len > 0 && report[0] == LONG_REPORT {
+            let len = len.min(bytes.len());
+            bytes[..len].copy_from_slice(&report[..len]);
+
+            return Ok(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod packet_tests {
+    use super::*;
+
+    fn all_variants() -> Vec<Packet> {
+        let mut packets = vec![];
+
+        for colour in [0x000000, 0xff00ff, 0x123456, 0xffffff] {
+            for region in 0..=5 {
+                packets.push(Packet::Colour { region, colour });
+            }
+
+            for speed in [32, 1000, 0xffff] {
+                for brightness in [0, DEFAULT_BRIGHTNESS, 0xff] {
+                    packets.push(Packet::Breathe {
+                        colour,
+                        speed,
+                        brightness,
+                    });
+                }
+            }
+        }
+
+        for speed in [32, 1000, 0xffff] {
+            for brightness in [0, DEFAULT_BRIGHTNESS, 0xff] {
+                packets.push(Packet::Cycle { speed, brightness });
+            }
+        }
+
+        packets
+    }
+
+    #[test]
+    fn round_trip_every_variant() {
+        for packet in all_variants() {
+            assert_eq!(Packet::decode(&packet.encode(G213_TEMPLATE)), Some(packet));
+        }
+    }
+
+    #[test]
+    fn colour_packet_bytes() {
+        let packet = Packet::Colour {
+            region: 2,
+            colour: 0x123456,
+        };
+
+        assert_eq!(
+            hex::encode(packet.encode(G213_TEMPLATE)),
+            "11ff0c3a02011234560200000000000000000000"
+        );
+    }
+
+    #[test]
+    fn breathe_packet_bytes() {
+        let packet = Packet::Breathe {
+            colour: 0x00ff00,
+            speed: 1000,
+            brightness: DEFAULT_BRIGHTNESS,
+        };
+
+        assert_eq!(
+            hex::encode(packet.encode(G213_TEMPLATE)),
+            "11ff0c3a000200ff0003e8006400000000000000"
+        );
+    }
+
+    #[test]
+    fn cycle_packet_bytes() {
+        let packet = Packet::Cycle {
+            speed: 1000,
+            brightness: DEFAULT_BRIGHTNESS,
+        };
+
+        assert_eq!(
+            hex::encode(packet.encode(G213_TEMPLATE)),
+            "11ff0c3a0003ffffff000003e864000000000000"
+        );
+    }
+
+    #[test]
+    fn decode_rejects_short_packet() {
+        assert_eq!(Packet::decode(&[0x11, 0xff, 0x0c, 0x3a]), None);
+    }
+
+    #[test]
+    fn decode_rejects_unknown_effect() {
+        let mut bytes = Packet::Cycle {
+            speed: 1000,
+            brightness: DEFAULT_BRIGHTNESS,
+        }
+        .encode(G213_TEMPLATE);
+        bytes[5] = 0x07;
+
+        assert_eq!(Packet::decode(&bytes), None);
+    }
+
+    #[test]
+    fn decode_rejects_trailing_garbage() {
+        let mut bytes = Packet::Colour {
+            region: 1,
+            colour: 0xff0000,
+        }
+        .encode(G213_TEMPLATE);
+        bytes[19] = 0x01;
+
+        assert_eq!(Packet::decode(&bytes), None);
+    }
+
+    #[test]
+    fn echoed_reply_is_accepted() {
+        let sent = Packet::Colour {
+            region: 0,
+            colour: 0xff0000,
+        }
+        .encode(G213_TEMPLATE);
+
+        let reply = Reply::parse(&sent).unwrap();
+
+        assert_eq!(reply.check_echo(&sent), Ok(()));
+    }
+
+    #[test]
+    fn error_frame_is_rejected() {
+        let reply = hex::decode("11ffff0c3a020000000000000000000000000000").unwrap();
+
+        assert_eq!(
+            Reply::parse(&reply),
+            Err(G213Error::Rejected {
+                feature_index: 0x0c,
+                function: 0x3a,
+                code: 0x02
+            })
+        );
+    }
+
+    #[test]
+    fn mismatched_reply_is_a_protocol_error() {
+        let sent = Packet::Cycle {
+            speed: 1000,
+            brightness: DEFAULT_BRIGHTNESS,
+        }
+        .encode(G213_TEMPLATE);
+        let mut reply = sent;
+        reply[3] = 0x1a;
+
+        assert!(matches!(
+            Reply::parse(&reply).unwrap().check_echo(&sent),
+            Err(G213Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn short_reply_is_a_protocol_error() {
+        assert!(matches!(
+            Reply::parse(&[0x11, 0xff]),
+            Err(G213Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn round_trip_other_template() {
+        let packet = Packet::Colour {
+            region: 3,
+            colour: 0xabcdef,
+        };
+        let template = PacketTemplate {
+            feature_index: 0x0e,
+            set_effect: 0x3c,
+        };
+
+        let bytes = packet.encode(template);
+
+        assert_eq!(bytes[2..4], [0x0e, 0x3c]);
+        assert_eq!(Packet::decode(&bytes), Some(packet));
+    }
+
+    #[test]
+    fn feature_request_bytes() {
+        assert_eq!(
+            hex::encode(feature_request(COLOUR_LED_EFFECTS)),
+            "11ff000a80700000000000000000000000000000"
+        );
+        assert!(is_feature_request(&feature_request(COLOUR_LED_EFFECTS)));
+    }
+
+    #[test]
+    fn feature_index_from_reply() {
+        let reply = Reply::parse(&hex::decode("11ff000a0e000000000000000000000000000000").unwrap())
+            .unwrap();
+
+        assert_eq!(reply.feature_index(), Some(0x0e));
+
+        let reply = Reply::parse(&hex::decode("11ff000a00000000000000000000000000000000").unwrap())
+            .unwrap();
+
+        assert_eq!(reply.feature_index(), None);
+    }
+
+    #[test]
+    fn other_reports_are_skipped() {
+        let mut reports = vec![
+            vec![0x01, 0x00, 0x04],
+            vec![0x10, 0xff, 0x0c, 0x3a, 0x00, 0x00, 0x00],
+            vec![LONG_REPORT, 0xff, 0x0c, 0x3a],
+        ]
+        .into_iter();
+        let mut bytes = [0u8; CMD_LEN];
+
+        let len = read_long_report(&mut bytes, Duration::from_millis(50), |report, _| {
+            let next = reports.next().ok_or(G213Error::Timeout)?;
+            report[..next.len()].copy_from_slice(&next);
+            Ok(next.len())
+        });
+
+        assert_eq!(len, Ok(4));
+        assert_eq!(bytes[..4], [LONG_REPORT, 0xff, 0x0c, 0x3a]);
+    }
+
+    #[test]
+    fn other_reports_cannot_keep_it_waiting() {
+        let mut reads = 0;
+        let mut bytes = [0u8; CMD_LEN];
+
+        let result = read_long_report(&mut bytes, Duration::from_millis(30), |report, left| {
+            reads += 1;
+            std::thread::sleep(left.min(Duration::from_millis(5)));
+            report[0] = 0x01;
+            Ok(8)
+        });
+
+        assert_eq!(result, Err(G213Error::Timeout));
+        assert!(reads <= 7);
+    }
+}
@@ -0,0 +1,42 @@
+use std::io;
+
+use crate::error::G213Error;
+
+// When installed setuid root, root is only needed to open and claim the
+// keyboard. Everything after that - config files, colour parsing and so on -
+// runs as the real user. Already open device handles keep working.
+pub fn drop_privileges() -> Result<(), G213Error> {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+    // Group first, we can't change it once we're no longer root
+    if unsafe { libc::setresgid(gid, gid, gid) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    if unsafe { libc::setresuid(uid, uid, uid) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    // Make sure there is no way back
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(G213Error::Io("Unable to drop root privileges".to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod privileges_tests {
+    use super::*;
+
+    #[test]
+    fn dropping_without_setuid_keeps_our_ids() {
+        let before = unsafe { (libc::getuid(), libc::geteuid(), libc::getgid()) };
+
+        assert_eq!(drop_privileges(), Ok(()));
+
+        let after = unsafe { (libc::getuid(), libc::geteuid(), libc::getgid()) };
+
+        assert_eq!(before, after);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::commands::Command;
+use crate::config::write_config_file;
+use crate::error::G213Error;
+use crate::state::LightingState;
+
+// `{ "version": 1, "profiles": { name: Profile, ... } }`
+pub const PROFILES_VERSION: u32 = 1;
+
+// A named lighting setup - the command and, if known, exactly what it
+// put on the keyboard
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Profile {
+    pub command: Command,
+    pub state: Option<LightingState>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileStore {
+    pub version: u32,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for ProfileStore {
+    fn default() -> Self {
+        ProfileStore {
+            version: PROFILES_VERSION,
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl ProfileStore {
+    // No file yet is just an empty store
+    pub fn load(path: &Path) -> Result<Self, G213Error> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(ProfileStore::default()),
+            Err(err) => return Err(G213Error::file("read", path, err)),
+        };
+
+        let store: ProfileStore = serde_json::from_str(&text)
+            .map_err(|err| G213Error::Io(format!("Unable to use {}: {}", path.display(), err)))?;
+
+        if store.version > PROFILES_VERSION {
+            return Err(G213Error::Io(format!(
+                "{} is from a newer g213-cols (version {})",
+                path.display(),
+                store.version
+            )));
+        }
+
+        Ok(store)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), G213Error> {
+        let ser_store = serde_json::to_string_pretty(self).unwrap();
+
+        write_config_file(path, ser_store.as_bytes())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    pub fn insert(&mut self, name: &str, profile: Profile) {
+        self.profiles.insert(name.to_string(), profile);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Profile> {
+        self.profiles.remove(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.profiles.keys()
+    }
+}
+
+#[cfg(test)]
+mod profiles_tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "g213-profiles-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    fn profile(colour: &str) -> Profile {
+        Profile {
+            command: Command::Colour(vec![colour.to_string()]),
+            state: None,
+        }
+    }
+
+    #[test]
+    fn missing_store_is_empty() {
+        let store = ProfileStore::load(&temp_file("missing")).unwrap();
+
+        assert_eq!(store.names().count(), 0);
+    }
+
+    #[test]
+    fn store_round_trips() {
+        let path = temp_file("round-trip");
+        let mut store = ProfileStore::default();
+
+        store.insert("meeting", profile("blue"));
+        store.insert("gaming", profile("red"));
+        store.save(&path).unwrap();
+
+        let store = ProfileStore::load(&path).unwrap();
+
+        assert_eq!(store.names().collect::<Vec<_>>(), vec!["gaming", "meeting"]);
+        assert!(matches!(
+            &store.get("meeting").unwrap().command,
+            Command::Colour(args) if args == &["blue"]
+        ));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn removed_profile_is_gone() {
+        let mut store = ProfileStore::default();
+
+        store.insert("focus", profile("green"));
+
+        assert!(store.remove("focus").is_some());
+        assert!(store.remove("focus").is_none());
+        assert!(store.get("focus").is_none());
+    }
+
+    #[test]
+    fn corrupt_store_is_an_error() {
+        let path = temp_file("corrupt");
+
+        fs::write(&path, "{\"version\":1,").unwrap();
+
+        assert!(ProfileStore::load(&path).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
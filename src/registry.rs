@@ -0,0 +1,673 @@
+use std::fmt::Display;
+
+use crate::commands::Command;
+use crate::g213_keyboard::NUM_REGIONS;
+use crate::suggest::closest;
+
+// What a command's arguments look like
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    // A whole number in a range
+    Number { min: u32, max: u32 },
+    // One of a fixed set of words
+    Choice(&'static [&'static str]),
+    // Any single word
+    Word,
+    // A single word naming something, which the shell completions can offer
+    File,
+    Profile,
+    Command,
+    // The rest of the arguments, as a colour or list of colours
+    Colour,
+    Colours,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+    pub help: &'static str,
+}
+
+const REGION_ARG: ArgSpec = ArgSpec {
+    name: "region",
+    kind: ArgKind::Number {
+        min: 1,
+        max: NUM_REGIONS as u32,
+    },
+    optional: false,
+    help: "the keyboard region",
+};
+
+const SPEED_ARG: ArgSpec = ArgSpec {
+    name: "speed",
+    kind: ArgKind::Number {
+        min: 0,
+        max: u16::MAX as u32,
+    },
+    optional: false,
+    help: "the time for one breath or cycle, in ms",
+};
+
+const COLOUR_ARG: ArgSpec = ArgSpec {
+    name: "colour",
+    kind: ArgKind::Colour,
+    optional: true,
+    help: "a hex colour, X11 colour name or 'random'",
+};
+
+const COLOURS_ARG: ArgSpec = ArgSpec {
+    name: "colour colour ...",
+    kind: ArgKind::Colours,
+    optional: true,
+    help: "one colour per region, the last is repeated",
+};
+
+#[derive(Debug, PartialEq)]
+pub enum ArgError {
+    Missing {
+        command: &'static str,
+        arg: &'static str,
+    },
+    Invalid {
+        command: &'static str,
+        arg: &'static str,
+        value: String,
+        expected: String,
+    },
+    TooMany {
+        command: &'static str,
+        usage: String,
+    },
+}
+
+impl Display for ArgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgError::Missing { command, arg } => {
+                write!(f, "'{}' argument needed for '{}' command", arg, command)
+            }
+            ArgError::Invalid {
+                command,
+                arg,
+                value,
+                expected,
+            } => write!(
+                f,
+                "Bad '{}' argument for '{}' command: '{}' - expected {}",
+                arg, command, value, expected
+            ),
+            ArgError::TooMany { command, usage } => {
+                write!(
+                    f,
+                    "Too many arguments for '{}' command, use: {}",
+                    command, usage
+                )
+            }
+        }
+    }
+}
+
+impl ArgKind {
+    pub fn expected(&self) -> String {
+        match self {
+            ArgKind::Number { min, max } => format!("a number from {} to {}", min, max),
+            ArgKind::Choice(choices) => format!("one of {}", choices.join(", ")),
+            ArgKind::Word => "a single word".to_string(),
+            ArgKind::File => "a file name".to_string(),
+            ArgKind::Profile => "a profile name".to_string(),
+            ArgKind::Command => "a command".to_string(),
+            ArgKind::Colour | ArgKind::Colours => "a colour".to_string(),
+        }
+    }
+
+    // Worth spelling out in the help
+    pub fn has_range(&self) -> bool {
+        matches!(self, ArgKind::Number { .. } | ArgKind::Choice(_))
+    }
+
+    // A number argument's value, if it is one and in range
+    fn number(&self, value: &str) -> Option<u32> {
+        match self {
+            ArgKind::Number { min, max } => value
+                .parse()
+                .ok()
+                .filter(|number| (*min..=*max).contains(number)),
+            _ => None,
+        }
+    }
+
+    fn check(&self, value: &str) -> bool {
+        match self {
+            ArgKind::Number { .. } => self.number(value).is_some(),
+            ArgKind::Choice(choices) => choices.contains(&value.to_lowercase().as_str()),
+            _ => true,
+        }
+    }
+
+    // Colours use up all the remaining arguments
+    pub fn is_rest(&self) -> bool {
+        matches!(self, ArgKind::Colour | ArgKind::Colours)
+    }
+}
+
+impl ArgSpec {
+    fn invalid(&self, command: &'static str, value: &str) -> ArgError {
+        ArgError::Invalid {
+            command,
+            arg: self.name,
+            value: value.to_string(),
+            expected: self.kind.expected(),
+        }
+    }
+}
+
+// A command line and what it does, the details can run over several lines
+pub struct Example {
+    pub usage: &'static str,
+    pub details: &'static [&'static str],
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static [ArgSpec],
+    pub help: &'static str,
+    pub examples: &'static [Example],
+    pub build: fn(Vec<String>) -> Command,
+}
+
+impl CommandSpec {
+    // eg `region region [colour]`
+    pub fn usage(&self) -> String {
+        let args = self.args.iter().map(|arg| {
+            if arg.optional {
+                format!("[{}]", arg.name)
+            } else {
+                arg.name.to_string()
+            }
+        });
+
+        [self.name.to_string()]
+            .into_iter()
+            .chain(args)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Choices are accepted in any case, but handled in lower case
+    pub fn normalise(&self, args: &[String]) -> Vec<String> {
+        args.iter()
+            .enumerate()
+            .map(|(index, arg)| match self.args.get(index) {
+                Some(spec) if matches!(spec.kind, ArgKind::Choice(_)) => arg.to_lowercase(),
+                _ => arg.clone(),
+            })
+            .collect()
+    }
+
+    // Checks `args` against the spec, and gives back the number arguments,
+    // already parsed
+    pub fn validate(&self, args: &[String]) -> Result<Vec<u32>, ArgError> {
+        let mut numbers = vec![];
+
+        for (index, spec) in self.args.iter().enumerate() {
+            match args.get(index) {
+                None if spec.optional => return Ok(numbers),
+                None => {
+                    return Err(ArgError::Missing {
+                        command: self.name,
+                        arg: spec.name,
+                    })
+                }
+                Some(_) if spec.kind.is_rest() => return Ok(numbers),
+                Some(value) => match spec.kind.number(value) {
+                    Some(number) => numbers.push(number),
+                    None if spec.kind.check(value) => (),
+                    None => return Err(spec.invalid(self.name, value)),
+                },
+            }
+        }
+
+        if args.len() > self.args.len() {
+            return Err(ArgError::TooMany {
+                command: self.name,
+                usage: self.usage(),
+            });
+        }
+
+        Ok(numbers)
+    }
+}
+
+pub static COMMANDS: [CommandSpec; 15] = [
+    CommandSpec {
+        name: "colour",
+        aliases: &["c"],
+        args: &[COLOUR_ARG],
+        help: "Set whole keyboard colour",
+        examples: &[
+            Example {
+                usage: "g213-cols colour",
+                details: &["sets the default 'white'"],
+            },
+            Example {
+                usage: "g213-cols colour ffff00",
+                details: &[
+                    "sets the specified hex colour, eg yellow",
+                    "NOTE: 3 digit hex colour conversion - 1af -> 11aaff",
+                ],
+            },
+            Example {
+                usage: "g213-cols colour lawn green",
+                details: &["sets the named X11 colour"],
+            },
+            Example {
+                usage: "g213-cols colour \"alice blue\"",
+                details: &["sets the named X11 colour - as a single argument"],
+            },
+            Example {
+                usage: "g213-cols colour dark_slate_blue",
+                details: &["sets the named X11 colour - underscores become spaces"],
+            },
+            Example {
+                usage: "g213-cols colour random",
+                details: &["sets a random colour"],
+            },
+        ],
+        build: Command::Colour,
+    },
+    CommandSpec {
+        name: "region",
+        aliases: &["r"],
+        args: &[REGION_ARG, COLOUR_ARG],
+        help: "Set the colour of a specific keyboard region - 1 to 5",
+        examples: &[Example {
+            usage: "g213-cols region 2 [colour]",
+            details: &["sets the region to the [colour] as defined above"],
+        }],
+        build: Command::Region,
+    },
+    CommandSpec {
+        name: "regions",
+        aliases: &["rs"],
+        args: &[COLOURS_ARG],
+        help: "Set the colours of all 5 keyboard regions",
+        examples: &[Example {
+            usage: "g213-cols regions [colour colour ...]",
+            details: &[
+                "sets the regions to the [colours] as defined above",
+                "last supplied colour is repeated to set all 5 regions",
+            ],
+        }],
+        build: Command::Regions,
+    },
+    CommandSpec {
+        name: "breathe",
+        aliases: &["b"],
+        args: &[SPEED_ARG, COLOUR_ARG],
+        help: "Set 'breathe' mode",
+        examples: &[Example {
+            usage: "g213-cols breathe 1000 [colour]",
+            details: &["sets the breathe time (in ms) for the [colour]"],
+        }],
+        build: Command::Breathe,
+    },
+    CommandSpec {
+        name: "cycle",
+        aliases: &["cy"],
+        args: &[SPEED_ARG],
+        help: "Set 'cycle' mode",
+        examples: &[Example {
+            usage: "g213-cols cycle 1000",
+            details: &["sets the cycle time (in ms) all colours"],
+        }],
+        build: Command::Cycle,
+    },
+    CommandSpec {
+        name: "saved",
+        aliases: &["s"],
+        args: &[],
+        help: "Use or show the last successful saved command",
+        examples: &[
+            Example {
+                usage: "g213-cols",
+                details: &[
+                    "Runs the last successful saved command",
+                    "from ~/.config/g213-cols/config.json",
+                    "restoring the same colours, even 'random' ones",
+                ],
+            },
+            Example {
+                usage: "g213-cols saved",
+                details: &["Display the last successful saved command"],
+            },
+        ],
+        build: |_| Command::Saved,
+    },
+    CommandSpec {
+        name: "watch",
+        aliases: &["w"],
+        args: &[],
+        help: "Restore saved command when a keyboard is plugged in",
+        examples: &[Example {
+            usage: "g213-cols watch",
+            details: &["Runs the saved command on connect, eg after suspend/replug"],
+        }],
+        build: |_| Command::Watch,
+    },
+    CommandSpec {
+        name: "profile",
+        aliases: &["p"],
+        args: &[
+            ArgSpec {
+                name: "action",
+                kind: ArgKind::Choice(&["save", "load", "list", "rm"]),
+                optional: false,
+                help: "what to do",
+            },
+            ArgSpec {
+                name: "name",
+                kind: ArgKind::Profile,
+                optional: true,
+                help: "the profile, not needed for list",
+            },
+        ],
+        help: "Named lighting profiles",
+        examples: &[
+            Example {
+                usage: "g213-cols profile save name",
+                details: &["keeps the saved command and its colours as profile 'name'"],
+            },
+            Example {
+                usage: "g213-cols profile load name",
+                details: &["sets the keyboard to profile 'name'"],
+            },
+            Example {
+                usage: "g213-cols profile list",
+                details: &["lists the saved profiles"],
+            },
+            Example {
+                usage: "g213-cols profile rm name",
+                details: &["removes profile 'name'"],
+            },
+        ],
+        build: Command::Profile,
+    },
+    CommandSpec {
+        name: "replay",
+        aliases: &["rp"],
+        args: &[ArgSpec {
+            name: "file",
+            kind: ArgKind::File,
+            optional: false,
+            help: "a file written with --capture",
+        }],
+        help: "Replay a capture file",
+        examples: &[Example {
+            usage: "g213-cols replay file",
+            details: &["Sends the packets recorded with `--capture file` again"],
+        }],
+        build: Command::Replay,
+    },
+    CommandSpec {
+        name: "setup-permissions",
+        aliases: &["sp"],
+        args: &[
+            ArgSpec {
+                name: "seat or group",
+                kind: ArgKind::Word,
+                optional: true,
+                help: "who gets access, the logged in seat by default",
+            },
+            ArgSpec {
+                name: "file",
+                kind: ArgKind::File,
+                optional: true,
+                help: "where the rule goes, - to print it",
+            },
+        ],
+        help: "Allow non-root access with a udev rule",
+        examples: &[Example {
+            usage: "g213-cols setup-permissions [seat or group] [file]",
+            details: &[
+                "writes a rule for the logged in seat (default) or a group",
+                "to [file], default /etc/udev/rules.d/70-g213-cols.rules",
+                "use - as [file] to print the rule instead",
+            ],
+        }],
+        build: Command::SetupPermissions,
+    },
+    CommandSpec {
+        name: "info",
+        aliases: &["i"],
+        args: &[],
+        help: "Show keyboard info",
+        examples: &[Example {
+            usage: "g213-cols info",
+            details: &["Show USB info for keyboard"],
+        }],
+        build: |_| Command::Info,
+    },
+    CommandSpec {
+        name: "devices",
+        aliases: &["d"],
+        args: &[],
+        help: "List connected keyboards",
+        examples: &[Example {
+            usage: "g213-cols devices",
+            details: &["Show bus, address, port path and serial for every keyboard"],
+        }],
+        build: |_| Command::Devices,
+    },
+    CommandSpec {
+        name: "list",
+        aliases: &["l"],
+        args: &[ArgSpec {
+            name: "name",
+            kind: ArgKind::Word,
+            optional: true,
+            help: "part of a colour name",
+        }],
+        help: "List X11 colours",
+        examples: &[Example {
+            usage: "g213-cols list [name]",
+            details: &["Show X11 colours that contain optional [name] or all colours"],
+        }],
+        build: Command::List,
+    },
+    CommandSpec {
+        name: "completions",
+        aliases: &[],
+        args: &[ArgSpec {
+            name: "shell",
+            kind: ArgKind::Choice(&["bash", "zsh", "fish"]),
+            optional: false,
+            help: "the shell to complete for",
+        }],
+        help: "Shell completions",
+        examples: &[
+            Example {
+                usage: "g213-cols completions bash",
+                details: &["prints a bash completion script, also zsh or fish"],
+            },
+            Example {
+                usage: "source <(g213-cols completions bash)",
+                details: &["completes commands, regions, colours and profiles"],
+            },
+        ],
+        build: Command::Completions,
+    },
+    CommandSpec {
+        name: "help",
+        aliases: &["h", "?"],
+        args: &[ArgSpec {
+            name: "command",
+            kind: ArgKind::Command,
+            optional: true,
+            help: "a command or abbreviation",
+        }],
+        help: "Show help",
+        examples: &[
+            Example {
+                usage: "g213-cols help",
+                details: &["Show all commands and options"],
+            },
+            Example {
+                usage: "g213-cols help region",
+                details: &["Show the arguments and examples for one command"],
+            },
+        ],
+        build: Command::Help,
+    },
+];
+
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    let name = name.to_lowercase();
+
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name.as_str()))
+}
+
+// Commands with a name like the mistyped one
+pub fn command_suggestions(name: &str) -> Vec<&'static str> {
+    closest(name, COMMANDS.iter().map(|spec| spec.name), |name| {
+        name.to_lowercase()
+    })
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use super::*;
+
+    fn to_string_vec(words: Vec<&str>) -> Vec<String> {
+        words.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn names_and_aliases_are_found() {
+        assert_eq!(find_command("region").unwrap().name, "region");
+        assert_eq!(find_command("RS").unwrap().name, "regions");
+        assert_eq!(find_command("?").unwrap().name, "help");
+        assert!(find_command("nonsense").is_none());
+    }
+
+    #[test]
+    fn mistyped_commands_are_suggested() {
+        assert_eq!(command_suggestions("colr"), vec!["colour"]);
+        assert_eq!(command_suggestions("Regon"), vec!["region", "regions"]);
+        assert!(command_suggestions("x").is_empty());
+    }
+
+    #[test]
+    fn names_and_aliases_are_unique() {
+        let mut all: Vec<_> = COMMANDS
+            .iter()
+            .flat_map(|spec| [spec.name].into_iter().chain(spec.aliases.iter().copied()))
+            .collect();
+        let count = all.len();
+
+        all.sort();
+        all.dedup();
+
+        assert_eq!(all.len(), count);
+    }
+
+    #[test]
+    fn usage_shows_optional_args() {
+        assert_eq!(
+            find_command("region").unwrap().usage(),
+            "region region [colour]"
+        );
+    }
+
+    #[test]
+    fn bad_number_names_argument_and_range() {
+        let err = find_command("region")
+            .unwrap()
+            .validate(&to_string_vec(vec!["x", "red"]))
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Bad 'region' argument for 'region' command: 'x' - expected a number from 1 to 5"
+        );
+
+        assert!(find_command("region")
+            .unwrap()
+            .validate(&to_string_vec(vec!["9"]))
+            .is_err());
+        assert!(find_command("breathe")
+            .unwrap()
+            .validate(&to_string_vec(vec!["70000"]))
+            .is_err());
+    }
+
+    #[test]
+    fn missing_and_extra_arguments() {
+        let cycle = find_command("cycle").unwrap();
+
+        assert_eq!(
+            cycle.validate(&[]),
+            Err(ArgError::Missing {
+                command: "cycle",
+                arg: "speed"
+            })
+        );
+        assert!(matches!(
+            cycle.validate(&to_string_vec(vec!["1000", "2000"])),
+            Err(ArgError::TooMany { .. })
+        ));
+        assert!(find_command("info")
+            .unwrap()
+            .validate(&to_string_vec(vec!["extra"]))
+            .is_err());
+    }
+
+    #[test]
+    fn colours_use_the_rest() {
+        let args = to_string_vec(vec!["1000", "alice", "blue"]);
+
+        assert_eq!(
+            find_command("breathe").unwrap().validate(&args),
+            Ok(vec![1000])
+        );
+        assert_eq!(
+            find_command("regions")
+                .unwrap()
+                .validate(&to_string_vec(vec!["red", "green", "blue"])),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn choices_are_checked() {
+        let profile = find_command("profile").unwrap();
+
+        assert_eq!(
+            profile.validate(&to_string_vec(vec!["load", "x"])),
+            Ok(vec![])
+        );
+        assert!(profile.validate(&to_string_vec(vec!["nuke"])).is_err());
+    }
+
+    #[test]
+    fn choices_are_normalised() {
+        let profile = find_command("profile").unwrap();
+
+        assert_eq!(
+            profile.normalise(&to_string_vec(vec!["LOAD", "Work"])),
+            to_string_vec(vec!["load", "Work"])
+        );
+    }
+
+    #[test]
+    fn number_argument() {
+        assert_eq!(REGION_ARG.kind.number("3"), Some(3));
+        assert_eq!(REGION_ARG.kind.number("0"), None);
+        assert_eq!(SPEED_ARG.kind.number("-1"), None);
+        assert_eq!(COLOUR_ARG.kind.number("3"), None);
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+use crate::device_profiles::DeviceProfile;
+use crate::error::G213Error;
+use crate::packet::Packet;
+use crate::transport::{DeviceInfo, Transport};
+
+// What a command actually put on the keyboard - effect, colours and speed -
+// once any random colours have been picked
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LightingState {
+    pub packets: Vec<Packet>,
+}
+
+impl LightingState {
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+// Notes down every lighting packet sent through another transport
+pub struct StateRecorder<'a, T: Transport + ?Sized> {
+    inner: &'a mut T,
+    state: &'a mut LightingState,
+}
+
+impl<'a, T: Transport + ?Sized> StateRecorder<'a, T> {
+    pub fn new(inner: &'a mut T, state: &'a mut LightingState) -> Self {
+        StateRecorder { inner, state }
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for StateRecorder<'_, T> {
+    fn open(&mut self) -> Result<(), G213Error> {
+        self.inner.open()
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        self.inner.claim()
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        self.inner.release()
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        let len = self.inner.write_control(bytes)?;
+
+        if let Some(packet) = Packet::decode(bytes) {
+            self.state.packets.push(packet);
+        }
+
+        Ok(len)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        self.inner.read_interrupt(bytes)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        self.inner.info()
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        self.inner.profile()
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+    use crate::g213_keyboard::{set_cycle, set_region_colours};
+    use crate::packet::DEFAULT_BRIGHTNESS;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn records_lighting_packets_only() {
+        let mut mock = MockTransport::new();
+        let mut state = LightingState::default();
+
+        set_region_colours(
+            &mut StateRecorder::new(&mut mock, &mut state),
+            &[0xff0000, 0x00ff00],
+        )
+        .unwrap();
+
+        assert_eq!(
+            state.packets,
+            vec![
+                Packet::Colour {
+                    region: 1,
+                    colour: 0xff0000
+                },
+                Packet::Colour {
+                    region: 2,
+                    colour: 0x00ff00
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn state_round_trips_through_json() {
+        let mut mock = MockTransport::new();
+        let mut state = LightingState::default();
+
+        set_cycle(&mut StateRecorder::new(&mut mock, &mut state), 1000).unwrap();
+
+        let json = serde_json::to_string(&state).unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<LightingState>(&json)
+                .unwrap()
+                .packets,
+            vec![Packet::Cycle {
+                speed: 1000,
+                brightness: DEFAULT_BRIGHTNESS
+            }]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+// Close matches for mistyped commands and colour names
+
+const MAX_SUGGESTIONS: usize = 3;
+
+// Levenshtein distance - the single character inserts, deletes and changes
+// needed to turn one word into the other
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b_char) in b.iter().enumerate() {
+            let change = diagonal + usize::from(a_char != *b_char);
+
+            diagonal = row[j + 1];
+            row[j + 1] = change.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+// The candidates near enough to `word`, closest first. Candidates that are
+// the same once `key` has been applied only appear once.
+pub fn closest<'a>(
+    word: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+    key: impl Fn(&str) -> String,
+) -> Vec<&'a str> {
+    let word = key(word);
+    // About one mistake in every three letters
+    let limit = word.chars().count().div_ceil(3);
+
+    let mut seen = vec![];
+    let mut near = vec![];
+
+    for candidate in candidates {
+        let candidate_key = key(candidate);
+
+        if seen.contains(&candidate_key) {
+            continue;
+        }
+
+        let distance = edit_distance(&word, &candidate_key);
+
+        if distance <= limit {
+            near.push((distance, candidate));
+        }
+
+        seen.push(candidate_key);
+    }
+
+    // Stable, so equally close candidates keep their order
+    near.sort_by_key(|(distance, _)| *distance);
+
+    near.into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+// eg " - did you mean 'region' or 'regions'?", or nothing
+pub fn did_you_mean(suggestions: &[&str]) -> String {
+    let quoted: Vec<_> = suggestions.iter().map(|s| format!("'{}'", s)).collect();
+
+    match quoted.split_last() {
+        None => String::new(),
+        Some((last, [])) => format!(" - did you mean {}?", last),
+        Some((last, rest)) => format!(" - did you mean {} or {}?", rest.join(", "), last),
+    }
+}
+
+#[cfg(test)]
+mod suggest_tests {
+    use super::*;
+
+    #[test]
+    fn distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("colour", "colour"), 0);
+        assert_eq!(edit_distance("colr", "colour"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn closest_first_and_far_ones_dropped() {
+        let words = ["regions", "region", "replay", "watch"];
+
+        assert_eq!(
+            closest("regon", words, |w| w.to_string()),
+            vec!["region", "regions"]
+        );
+        assert!(closest("zzzzzz", words, |w| w.to_string()).is_empty());
+    }
+
+    #[test]
+    fn same_key_appears_once() {
+        let words = ["lawn green", "lawngreen", "LawnGreen"];
+
+        assert_eq!(
+            closest("lawngren", words, |w| w.to_lowercase().replace(' ', "")),
+            vec!["lawn green"]
+        );
+    }
+
+    #[test]
+    fn did_you_mean_lists() {
+        assert_eq!(did_you_mean(&[]), "");
+        assert_eq!(did_you_mean(&["blue"]), " - did you mean 'blue'?");
+        assert_eq!(
+            did_you_mean(&["a", "b", "c"]),
+            " - did you mean 'a', 'b' or 'c'?"
+        );
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+
+use crate::device_profiles::{DeviceProfile, G213_PROFILE};
+use crate::error::G213Error;
+use crate::packet::{is_feature_request, DEFAULT_FEATURE_INDEX};
+
+// Basic identifying information for a connected keyboard
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub port_path: String,
+    pub speed: String,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Device bus:   {}", self.bus)?;
+        writeln!(f, "Device #:     {}", self.address)?;
+        writeln!(f, "Port path:    {}", self.port_path)?;
+        writeln!(f, "Device speed: {}", self.speed)?;
+        writeln!(f, "Manufacturer: {}", self.manufacturer)?;
+        writeln!(f, "Product:      {}", self.product)?;
+        write!(f, "Serial:       {}", self.serial)
+    }
+}
+
+// Picks one keyboard when several are plugged in
+//
+//   1:5       - bus 1, address 5
+//   1-2.3     - port path, as used in /sys/bus/usb/devices
+//   anything else is taken as a serial number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    BusAddress(u8, u8),
+    PortPath(String),
+    Serial(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Empty device selector".to_string());
+        }
+
+        if let Some((bus, address)) = s.split_once(':') {
+            return match (bus.parse::<u8>(), address.parse::<u8>()) {
+                (Ok(bus), Ok(address)) => Ok(DeviceSelector::BusAddress(bus, address)),
+                _ => Err(format!("Invalid 'bus:address' device selector: {}", s)),
+            };
+        }
+
+        let is_port_path = s.contains('-')
+            && s.chars()
+                .all(|c| c.is_ascii_digit() || c == '-' || c == '.');
+
+        if is_port_path {
+            Ok(DeviceSelector::PortPath(s.to_string()))
+        } else {
+            Ok(DeviceSelector::Serial(s.to_string()))
+        }
+    }
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::BusAddress(bus, address) => {
+                info.bus == *bus && info.address == *address
+            }
+            DeviceSelector::PortPath(path) => info.port_path == *path,
+            DeviceSelector::Serial(serial) => info.serial.eq_ignore_ascii_case(serial),
+        }
+    }
+}
+
+// Everything the keyboard layer needs from a device backend
+//
+// The usual sequence is open -> claim -> (write_control -> read_interrupt)* -> release
+pub trait Transport {
+    fn open(&mut self) -> Result<(), G213Error>;
+    fn claim(&mut self) -> Result<(), G213Error>;
+    fn release(&mut self) -> Result<(), G213Error>;
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error>;
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error>;
+    fn info(&mut self) -> Result<DeviceInfo, G213Error>;
+    fn profile(&self) -> &'static DeviceProfile;
+}
+
+// So that the backend can be picked at run time
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn open(&mut self) -> Result<(), G213Error> {
+        (**self).open()
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        (**self).claim()
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        (**self).release()
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        (**self).write_control(bytes)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        (**self).read_interrupt(bytes)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        (**self).info()
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        (**self).profile()
+    }
+}
+
+// So that a guard can hold on to a borrowed transport
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn open(&mut self) -> Result<(), G213Error> {
+        (**self).open()
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        (**self).claim()
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        (**self).release()
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        (**self).write_control(bytes)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        (**self).read_interrupt(bytes)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        (**self).info()
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        (**self).profile()
+    }
+}
+
+// Owns a claimed interface and always hands it back to the kernel when
+// dropped, including while unwinding from a panic
+pub struct InterfaceGuard<T: Transport> {
+    transport: T,
+    released: bool,
+}
+
+impl<T: Transport> InterfaceGuard<T> {
+    pub fn claim(mut transport: T) -> Result<Self, G213Error> {
+        transport.open()?;
+        transport.claim()?;
+
+        Ok(InterfaceGuard {
+            transport,
+            released: false,
+        })
+    }
+
+    // Release explicitly so that any failure can be reported
+    pub fn release(mut self) -> Result<(), G213Error> {
+        self.released = true;
+
+        self.transport.release()
+    }
+}
+
+impl<T: Transport> Deref for InterfaceGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.transport
+    }
+}
+
+impl<T: Transport> DerefMut for InterfaceGuard<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.transport
+    }
+}
+
+impl<T: Transport> Drop for InterfaceGuard<T> {
+    fn drop(&mut self) {
+        if !self.released {
+            if let Err(err) = self.transport.release() {
+                eprintln!("{}", err);
+            }
+        }
+    }
+}
+
+// A guarded transport is already claimed, and stays claimed until the guard
+// is released - so claims made through it, eg by a `Session`, do nothing
+impl<T: Transport> Transport for InterfaceGuard<T> {
+    fn open(&mut self) -> Result<(), G213Error> {
+        Ok(())
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        Ok(())
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        self.transport.write_control(bytes)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        self.transport.read_interrupt(bytes)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        self.transport.info()
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        self.transport.profile()
+    }
+}
+
+// For commands that don't need a keyboard - any use is an error
+pub struct NoKeyboard;
+
+impl Transport for NoKeyboard {
+    fn open(&mut self) -> Result<(), G213Error> {
+        Err(G213Error::NotFound)
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        Err(G213Error::NotFound)
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        Err(G213Error::NotFound)
+    }
+
+    fn write_control(&mut self, _bytes: &[u8]) -> Result<usize, G213Error> {
+        Err(G213Error::NotFound)
+    }
+
+    fn read_interrupt(&mut self, _bytes: &mut [u8]) -> Result<usize, G213Error> {
+        Err(G213Error::NotFound)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        Err(G213Error::NotFound)
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        &G213_PROFILE
+    }
+}
+
+// A keyboard that isn't there - records everything sent to it. Feature
+// requests are answered with `feature_index`, other packets with any queued
+// `replies`, otherwise they are echoed back
+#[derive(Debug)]
+pub struct MockTransport {
+    pub opened: bool,
+    pub claimed: bool,
+    pub claims: usize,
+    pub releases: usize,
+    pub written: Vec<Vec<u8>>,
+    pub replies: VecDeque<Vec<u8>>,
+    pub feature_index: u8,
+    pub info: DeviceInfo,
+    pub profile: &'static DeviceProfile,
+    pub fail_writes: Option<G213Error>,
+    pub fail_release: Option<G213Error>,
+    pub fail_info: Option<G213Error>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        MockTransport {
+            opened: false,
+            claimed: false,
+            claims: 0,
+            releases: 0,
+            written: vec![],
+            replies: VecDeque::new(),
+            feature_index: DEFAULT_FEATURE_INDEX,
+            info: DeviceInfo {
+                manufacturer: "Logitech".to_string(),
+                product: "G213 Prodigy Gaming Keyboard".to_string(),
+                serial: "MOCK0001".to_string(),
+                bus: 1,
+                address: 5,
+                port_path: "1-2".to_string(),
+                speed: "Full".to_string(),
+            },
+            profile: &G213_PROFILE,
+            fail_writes: None,
+            fail_release: None,
+            fail_info: None,
+        }
+    }
+
+    // What was sent, less any feature discovery
+    pub fn effect_packets(&self) -> Vec<Vec<u8>> {
+        self.written
+            .iter()
+            .filter(|bytes| !is_feature_request(bytes))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for MockTransport {
+    fn open(&mut self) -> Result<(), G213Error> {
+        self.opened = true;
+        Ok(())
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        if !self.opened {
+            return Err(G213Error::NotFound);
+        }
+        if self.claimed {
+            return Err(G213Error::Busy);
+        }
+
+        self.claimed = true;
+        self.claims += 1;
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        if !self.claimed {
+            return Err(G213Error::Protocol("interface not claimed".to_string()));
+        }
+
+        self.claimed = false;
+        self.releases += 1;
+
+        match &self.fail_release {
+            Some(err) => Err(err.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        if !self.claimed {
+            return Err(G213Error::PermissionDenied);
+        }
+        if let Some(err) = &self.fail_writes {
+            return Err(err.clone());
+        }
+
+        self.written.push(bytes.to_vec());
+        Ok(bytes.len())
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        let last = self.written.last().ok_or(G213Error::Timeout)?;
+
+        let reply = if is_feature_request(last) {
+            let mut reply = last.clone();
+            reply[4..].fill(0);
+            reply[4] = self.feature_index;
+            reply
+        } else {
+            match self.replies.pop_front() {
+                Some(reply) => reply,
+                None => last.clone(),
+            }
+        };
+
+        let len = reply.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&reply[..len]);
+        Ok(len)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        match &self.fail_info {
+            Some(err) => Err(err.clone()),
+            None => Ok(self.info.clone()),
+        }
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        self.profile
+    }
+}
+
+// Prints each lighting packet instead of sending it - otherwise behaves
+// like a `MockTransport`, but without pretending to be a real keyboard
+#[derive(Debug, Default)]
+pub struct DryRunTransport {
+    pub mock: MockTransport,
+}
+
+impl Transport for DryRunTransport {
+    fn open(&mut self) -> Result<(), G213Error> {
+        self.mock.open()
+    }
+
+    fn claim(&mut self) -> Result<(), G213Error> {
+        self.mock.claim()
+    }
+
+    fn release(&mut self) -> Result<(), G213Error> {
+        self.mock.release()
+    }
+
+    fn write_control(&mut self, bytes: &[u8]) -> Result<usize, G213Error> {
+        if !is_feature_request(bytes) {
+            println!("{}", hex::encode(bytes));
+        }
+
+        self.mock.write_control(bytes)
+    }
+
+    fn read_interrupt(&mut self, bytes: &mut [u8]) -> Result<usize, G213Error> {
+        self.mock.read_interrupt(bytes)
+    }
+
+    fn info(&mut self) -> Result<DeviceInfo, G213Error> {
+        Err(G213Error::Unsupported(
+            "No keyboard is queried in a dry run".to_string(),
+        ))
+    }
+
+    fn profile(&self) -> &'static DeviceProfile {
+        self.mock.profile()
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::*;
+    use crate::g213_keyboard::set_region_colour;
+
+    #[test]
+    fn mock_must_be_claimed_before_writing() {
+        let mut mock = MockTransport::new();
+
+        assert_eq!(
+            mock.write_control(&[1, 2, 3]),
+            Err(G213Error::PermissionDenied)
+        );
+
+        mock.open().unwrap();
+        mock.claim().unwrap();
+
+        assert_eq!(mock.write_control(&[1, 2, 3]), Ok(3));
+        assert_eq!(mock.written, vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn mock_echoes_last_packet() {
+        let mut mock = MockTransport::new();
+        mock.open().unwrap();
+        mock.claim().unwrap();
+        mock.write_control(&[4, 5, 6]).unwrap();
+
+        let mut reply = [0u8; 3];
+
+        assert_eq!(mock.read_interrupt(&mut reply), Ok(3));
+        assert_eq!(reply, [4, 5, 6]);
+    }
+
+    #[test]
+    fn mock_cannot_be_claimed_twice() {
+        let mut mock = MockTransport::new();
+        mock.open().unwrap();
+        mock.claim().unwrap();
+
+        assert_eq!(mock.claim(), Err(G213Error::Busy));
+    }
+
+    #[test]
+    fn guard_releases_on_drop() {
+        let mut mock = MockTransport::new();
+
+        {
+            let mut guard = InterfaceGuard::claim(&mut mock).unwrap();
+            guard.write_control(&[1]).unwrap();
+        }
+
+        assert!(!mock.claimed);
+        assert_eq!(mock.releases, 1);
+    }
+
+    #[test]
+    fn guard_releases_when_unwinding() {
+        let mut mock = MockTransport::new();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = InterfaceGuard::claim(&mut mock).unwrap();
+            panic!("command failed");
+        }));
+
+        assert!(result.is_err());
+        assert!(!mock.claimed);
+        assert_eq!(mock.releases, 1);
+    }
+
+    #[test]
+    fn guard_reports_release_failure_once() {
+        let mut mock = MockTransport::new();
+        mock.fail_release = Some(G213Error::DriverReattach(rusb::Error::Io));
+
+        let guard = InterfaceGuard::claim(&mut mock).unwrap();
+
+        assert_eq!(
+            guard.release(),
+            Err(G213Error::DriverReattach(rusb::Error::Io))
+        );
+        assert_eq!(mock.releases, 1);
+    }
+
+    #[test]
+    fn claims_through_a_guard_are_held_by_it() {
+        let mut guard = InterfaceGuard::claim(MockTransport::new()).unwrap();
+
+        set_region_colour(&mut guard, 1, 0xff0000).unwrap();
+
+        assert!(guard.claimed);
+        assert_eq!(guard.claims, 1);
+        assert_eq!(guard.effect_packets().len(), 1);
+
+        guard.release().unwrap();
+    }
+
+    #[test]
+    fn guard_not_created_if_claim_fails() {
+        let mut mock = MockTransport::new();
+        mock.open().unwrap();
+        mock.claim().unwrap();
+
+        assert_eq!(
+            InterfaceGuard::claim(&mut mock).err(),
+            Some(G213Error::Busy)
+        );
+        assert_eq!(mock.releases, 0);
+    }
+
+    #[test]
+    fn dry_run_has_no_device_info() {
+        let mut dry_run = DryRunTransport::default();
+
+        assert!(dry_run.info().is_err());
+    }
+
+    #[test]
+    fn parse_device_selectors() {
+        assert_eq!(
+            "1:5".parse::<DeviceSelector>(),
+            Ok(DeviceSelector::BusAddress(1, 5))
+        );
+        assert_eq!(
+            "3-1.4".parse::<DeviceSelector>(),
+            Ok(DeviceSelector::PortPath("3-1.4".to_string()))
+        );
+        assert_eq!(
+            "1A2B3C4D".parse::<DeviceSelector>(),
+            Ok(DeviceSelector::Serial("1A2B3C4D".to_string()))
+        );
+        assert!("1:x".parse::<DeviceSelector>().is_err());
+        assert!("".parse::<DeviceSelector>().is_err());
+    }
+
+    #[test]
+    fn device_selectors_match_info() {
+        let info = MockTransport::new().info;
+
+        assert!(DeviceSelector::BusAddress(1, 5).matches(&info));
+        assert!(!DeviceSelector::BusAddress(1, 6).matches(&info));
+        assert!(DeviceSelector::PortPath("1-2".to_string()).matches(&info));
+        assert!(DeviceSelector::Serial("mock0001".to_string()).matches(&info));
+        assert!(!DeviceSelector::Serial("other".to_string()).matches(&info));
+    }
+}
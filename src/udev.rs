@@ -0,0 +1,244 @@
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::replace_file;
+use crate::device_profiles::{DeviceProfile, DEVICE_PROFILES};
+use crate::error::G213Error;
+use crate::g213_keyboard::LOGITECH;
+use crate::hidraw::{find_hidraw_nodes, DEV_ROOT, SYSFS_ROOT};
+
+pub const DEFAULT_RULES_PATH: &str = "/etc/udev/rules.d/70-g213-cols.rules";
+
+// Who gets to use the keyboards
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    // Whoever is logged in at the local seat, via systemd-logind
+    Seat,
+    // Members of a group, eg plugdev
+    Group(String),
+}
+
+impl Access {
+    // `seat`, or a group name as groupadd would take it: up to 32 lower case
+    // letters, digits, '_' or '-', not starting with a digit or '-'
+    pub fn parse(name: &str) -> Result<Self, String> {
+        if name == "seat" {
+            return Ok(Access::Seat);
+        }
+
+        let mut chars = name.chars();
+        let valid = name.len() <= 32
+            && chars
+                .next()
+                .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+            && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_-".contains(c));
+
+        if valid {
+            Ok(Access::Group(name.to_string()))
+        } else {
+            Err(format!("'{}' isn't 'seat' or a valid group name", name))
+        }
+    }
+
+    fn assignment(&self) -> String {
+        match self {
+            Access::Seat => "TAG+=\"uaccess\"".to_string(),
+            Access::Group(group) => format!("GROUP=\"{}\"", group),
+        }
+    }
+}
+
+fn profile_rules(profile: &DeviceProfile, access: &Access) -> String {
+    let mut rules = format!("# {}\n", profile.name);
+
+    for product in profile.product_ids {
+        let vendor = format!("{:04x}", LOGITECH);
+        let product = format!("{:04x}", product);
+
+        rules += &format!(
+            "SUBSYSTEM==\"usb\", ENV{{DEVTYPE}}==\"usb_device\", ATTR{{idVendor}}==\"{}\", ATTR{{idProduct}}==\"{}\", MODE=\"0660\", {}\n",
+            vendor,
+            product,
+            access.assignment()
+        );
+        rules += &format!(
+            "KERNEL==\"hidraw*\", SUBSYSTEM==\"hidraw\", ATTRS{{idVendor}}==\"{}\", ATTRS{{idProduct}}==\"{}\", MODE=\"0660\", {}\n",
+            vendor,
+            product,
+            access.assignment()
+        );
+    }
+
+    rules
+}
+
+// udev rules giving access to the USB and hidraw nodes of every supported device
+pub fn udev_rules(access: &Access) -> String {
+    let mut rules = String::from(
+        "# Access to Logitech RGB keyboards for g213-cols, without root\n\
+         # Generated by `g213-cols setup-permissions`\n",
+    );
+
+    for profile in DEVICE_PROFILES {
+        rules += "\n";
+        rules += &profile_rules(profile, access);
+    }
+
+    rules
+}
+
+// Run as root, so never through a symlink, and the rules stay root's
+pub fn install_rules(path: &Path, rules: &str) -> Result<(), G213Error> {
+    replace_file(path, rules.as_bytes(), 0o644, None)
+}
+
+// Have udev pick up the new rules and apply them to devices already plugged in
+pub fn reload_udev() -> Result<(), G213Error> {
+    let steps: [&[&str]; 2] = [
+        &["control", "--reload-rules"],
+        &[
+            "trigger",
+            "--settle",
+            "--subsystem-match=usb",
+            "--subsystem-match=hidraw",
+        ],
+    ];
+
+    for args in steps {
+        let status = Command::new("udevadm")
+            .args(args)
+            .status()
+            .map_err(|err| G213Error::Io(format!("Unable to run udevadm: {}", err)))?;
+
+        if !status.success() {
+            return Err(G213Error::Io(format!(
+                "udevadm {} failed: {}",
+                args.join(" "),
+                status
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn usb_node_path(dev_root: &Path, bus: u8, address: u8) -> PathBuf {
+    dev_root.join(format!("bus/usb/{:03}/{:03}", bus, address))
+}
+
+// access(2) checks with the real user id, so this is right for setuid too
+fn can_read_write(path: &Path) -> bool {
+    let Ok(c_path) = CString::new(path.to_string_lossy().as_bytes()) else {
+        return false;
+    };
+
+    unsafe { libc::access(c_path.as_ptr(), libc::R_OK | libc::W_OK) == 0 }
+}
+
+// Every device node of every connected keyboard, and whether we can use it
+pub fn check_access() -> Vec<(PathBuf, bool)> {
+    let dev_root = Path::new(DEV_ROOT);
+
+    find_hidraw_nodes(Path::new(SYSFS_ROOT), dev_root)
+        .into_iter()
+        .flat_map(|node| {
+            [
+                node.path.clone(),
+                usb_node_path(dev_root, node.info.bus, node.info.address),
+            ]
+        })
+        .map(|path| {
+            let ok = can_read_write(&path);
+            (path, ok)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod udev_tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn access_names() {
+        assert_eq!(Access::parse("seat"), Ok(Access::Seat));
+        assert_eq!(
+            Access::parse("plugdev"),
+            Ok(Access::Group("plugdev".to_string()))
+        );
+
+        for bad in ["-", "", "Plugdev", "1abc", "a\"b", "/tmp/x.rules"] {
+            assert!(Access::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn seat_rules_use_uaccess() {
+        let rules = udev_rules(&Access::Seat);
+
+        assert!(rules.contains(
+            "SUBSYSTEM==\"usb\", ENV{DEVTYPE}==\"usb_device\", ATTR{idVendor}==\"046d\", ATTR{idProduct}==\"c336\", MODE=\"0660\", TAG+=\"uaccess\"\n"
+        ));
+        assert!(rules.contains(
+            "KERNEL==\"hidraw*\", SUBSYSTEM==\"hidraw\", ATTRS{idVendor}==\"046d\", ATTRS{idProduct}==\"c336\", MODE=\"0660\", TAG+=\"uaccess\"\n"
+        ));
+        assert!(!rules.contains("GROUP="));
+    }
+
+    #[test]
+    fn group_rules_cover_every_profile() {
+        let rules = udev_rules(&Access::Group("plugdev".to_string()));
+
+        for product in ["c336", "c084", "c33a"] {
+            let lines = rules
+                .lines()
+                .filter(|line| {
+                    line.contains(&format!("ATTR{{idProduct}}==\"{}\"", product))
+                        || line.contains(&format!("ATTRS{{idProduct}}==\"{}\"", product))
+                })
+                .filter(|line| line.ends_with("GROUP=\"plugdev\""))
+                .count();
+
+            assert_eq!(lines, 2);
+        }
+    }
+
+    #[test]
+    fn usb_node_paths() {
+        assert_eq!(
+            usb_node_path(Path::new("/dev"), 1, 7),
+            PathBuf::from("/dev/bus/usb/001/007")
+        );
+    }
+
+    #[test]
+    fn rules_are_written() {
+        let path = std::env::temp_dir().join(format!("g213-udev-{}.rules", std::process::id()));
+        let rules = udev_rules(&Access::Seat);
+
+        install_rules(&path, &rules).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), rules);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rules_are_not_written_through_a_symlink() {
+        let dir = std::env::temp_dir().join(format!("g213-udev-link-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let target = dir.join("target");
+        let path = dir.join("70-g213-cols.rules");
+
+        fs::write(&target, "precious").unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+
+        assert!(install_rules(&path, &udev_rules(&Access::Seat)).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "precious");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
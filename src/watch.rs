@@ -0,0 +1,158 @@
+use std::sync::mpsc::{channel, Sender};
+use std::time::{Duration, Instant};
+
+use rusb::constants::LIBUSB_CAP_HAS_HOTPLUG;
+use rusb::{ffi, Context, Device, Hotplug, HotplugBuilder, UsbContext};
+
+use crate::error::G213Error;
+use crate::g213_keyboard::{is_supported_device, usb_context, LOGITECH};
+
+// How long things must be quiet after a keyboard arrives before we act.
+// A hub reset can attach the same keyboard several times in quick succession.
+const SETTLE_MS: u64 = 1000;
+const POLL_MS: u64 = 250;
+
+// Fires once, `quiet` after the last of a burst of triggers
+pub struct Debouncer {
+    quiet: Duration,
+    pending: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(quiet: Duration) -> Self {
+        Debouncer {
+            quiet,
+            pending: None,
+        }
+    }
+
+    pub fn trigger(&mut self, now: Instant) {
+        self.pending = Some(now);
+    }
+
+    pub fn ready(&mut self, now: Instant) -> bool {
+        match self.pending {
+            Some(last) if now.duration_since(last) >= self.quiet => {
+                self.pending = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_supported(device: &Device<Context>) -> bool {
+    device
+        .device_descriptor()
+        .is_ok_and(|desc| is_supported_device(&desc))
+}
+
+// Hotplug callbacks can't talk to the device, so just pass arrivals on
+struct Arrivals {
+    sender: Sender<()>,
+}
+
+impl Hotplug<Context> for Arrivals {
+    fn device_arrived(&mut self, device: Device<Context>) {
+        if is_supported(&device) {
+            let _ = self.sender.send(());
+        }
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        if is_supported(&device) {
+            eprintln!("Keyboard disconnected");
+        }
+    }
+}
+
+// Calls `on_arrival` whenever a supported keyboard is plugged in (and once at the
+// start for any that already are). Only returns if something goes wrong.
+pub fn watch(on_arrival: impl FnMut()) -> Result<(), G213Error> {
+    watch_context(usb_context(), on_arrival)
+}
+
+// libusb has to be up before asking it about hotplug. rusb's `has_hotplug`
+// starts its own global context, which panics when there is no USB.
+fn watch_context(
+    context: Result<Context, G213Error>,
+    mut on_arrival: impl FnMut(),
+) -> Result<(), G213Error> {
+    let context = context?;
+
+    if unsafe { ffi::libusb_has_capability(LIBUSB_CAP_HAS_HOTPLUG) } == 0 {
+        return Err(G213Error::Protocol(
+            "libusb hotplug support is not available".to_string(),
+        ));
+    }
+
+    let (sender, receiver) = channel();
+
+    let _registration = HotplugBuilder::new()
+        .vendor_id(LOGITECH)
+        .enumerate(true)
+        .register(&context, Box::new(Arrivals { sender }))?;
+
+    let mut debouncer = Debouncer::new(Duration::from_millis(SETTLE_MS));
+
+    loop {
+        context.handle_events(Some(Duration::from_millis(POLL_MS)))?;
+
+        while receiver.try_recv().is_ok() {
+            debouncer.trigger(Instant::now());
+        }
+
+        if debouncer.ready(Instant::now()) {
+            on_arrival();
+        }
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    #[test]
+    fn no_usb_is_reported() {
+        let no_usb = Err(G213Error::NoUsb(rusb::Error::Other));
+        let mut arrivals = 0;
+
+        let result = watch_context(no_usb, || arrivals += 1);
+
+        assert!(matches!(result, Err(G213Error::NoUsb(_))));
+        assert_eq!(arrivals, 0);
+    }
+
+    #[test]
+    fn nothing_to_do_without_a_trigger() {
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+
+        assert!(!debouncer.ready(Instant::now()));
+    }
+
+    #[test]
+    fn fires_once_after_quiet_period() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+
+        debouncer.trigger(start);
+
+        assert!(!debouncer.ready(start + Duration::from_millis(50)));
+        assert!(debouncer.ready(start + Duration::from_millis(100)));
+        assert!(!debouncer.ready(start + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn repeated_triggers_are_collapsed() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+
+        debouncer.trigger(start);
+        debouncer.trigger(start + Duration::from_millis(80));
+        debouncer.trigger(start + Duration::from_millis(160));
+
+        assert!(!debouncer.ready(start + Duration::from_millis(200)));
+        assert!(debouncer.ready(start + Duration::from_millis(260)));
+        assert!(!debouncer.ready(start + Duration::from_millis(400)));
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 
 use rand::random;
 
+use crate::suggest::closest;
+
 const X11_COLOURS: &str = include_str!("../rgb.txt");
 
 type ColourLookup = HashMap<String, u32>;
@@ -121,14 +123,60 @@ pub fn get_x11_colour(args: &[String]) -> Option<u32> {
     colour
 }
 
+// Where the words stop making colours, if they do
+fn unmatched_from(args: &[String]) -> Option<usize> {
+    let mut col_str = String::new();
+    let mut start = 0;
+
+    for (index, arg) in args.iter().enumerate() {
+        col_str += arg;
+
+        if get_x11_colour(&[col_str.clone()]).is_some() {
+            col_str.truncate(0);
+            start = index + 1;
+        }
+    }
+
+    (start < args.len()).then_some(start)
+}
+
+// The shortest run of words that isn't a colour, once the words after it
+// are, eg 'bluee' from 'bluee green'
+fn unknown_words(args: &[String]) -> String {
+    let end = (1..=args.len())
+        .find(|&end| unmatched_from(&args[end..]).is_none())
+        .unwrap_or(args.len());
+
+    args[..end].join(" ")
+}
+
+// Similar colour names, ignoring case, spaces and underscores
+pub fn colour_suggestions(name: &str) -> Vec<&'static str> {
+    let names = COLOUR_NAMES
+        .iter()
+        .map(|name| name.as_str())
+        .chain([RANDOM, RANDOM_X11]);
+
+    closest(name, names, |name| {
+        name.to_ascii_lowercase().replace([' ', '_'], "")
+    })
+}
+
 pub fn get_x11_colours(args: &[String], num: u8) -> Option<Vec<u32>> {
+    find_x11_colours(args, num).ok()
+}
+
+// As `get_x11_colours`, but gives back any words that aren't a colour
+pub fn find_x11_colours(args: &[String], num: u8) -> Result<Vec<u32>, String> {
     let mut col_str: String = "".to_string();
     let mut n: u8 = 0;
     let mut cols = Vec::<u32>::new();
     let mut last_col_str: String = "".to_string();
+    // Where the words in col_str start
+    let mut start = 0;
 
     if !args.is_empty() {
-        for arg in args {
+        for (index, arg) in args.iter().enumerate() {
             col_str += arg;
 
             if let Some(col) = get_x11_colour(&[col_str.clone()]) {
@@ -137,6 +185,7 @@ pub fn get_x11_colours(args: &[String], num: u8) -> Option<Vec<u32>> {
 
                 last_col_str = col_str.clone();
                 col_str.truncate(0);
+                start = index + 1;
             }
 
             if n == num {
@@ -155,10 +204,12 @@ pub fn get_x11_colours(args: &[String], num: u8) -> Option<Vec<u32>> {
         }
     }
 
-    if n == num {
-        Some(cols)
+    if !col_str.is_empty() {
+        Err(unknown_words(&args[start..]))
+    } else if n == num {
+        Ok(cols)
     } else {
-        None
+        Err(args.join(" "))
     }
 }
 
@@ -389,6 +440,33 @@ mod x11_colours_tests {
         assert_eq!(get_x11_colours(&args, 2), Some(vec![0xf0f8ff, 0xc71585]));
     }
 
+    #[test]
+    fn unknown_colour_in_list_is_an_error() {
+        let args = to_string_vec(vec!["red", "bluee"]);
+
+        assert_eq!(find_x11_colours(&args, 5), Err("bluee".to_string()));
+        assert_eq!(get_x11_colours(&args, 5), None);
+    }
+
+    #[test]
+    fn unknown_colour_in_the_middle_is_reported_alone() {
+        let args = to_string_vec(vec!["red", "bluee", "green"]);
+
+        assert_eq!(find_x11_colours(&args, 5), Err("bluee".to_string()));
+
+        let args = to_string_vec(vec!["lawn", "gren", "alice", "blue"]);
+
+        assert_eq!(find_x11_colours(&args, 5), Err("lawn gren".to_string()));
+    }
+
+    #[test]
+    fn suggests_similar_colours() {
+        assert_eq!(colour_suggestions("lawngren"), vec!["lawn green"]);
+        assert_eq!(colour_suggestions("Alice_Bleu")[0], "alice blue");
+        assert_eq!(colour_suggestions("randon"), vec!["random"]);
+        assert!(colour_suggestions("zzzzzzzz").is_empty());
+    }
+
     #[test]
     fn adjust_3_digit_111() {
         assert_eq!(adjust_3_digit_colour(0x111), 0x111111);